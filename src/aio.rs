@@ -0,0 +1,368 @@
+//!
+//! # Async API
+//!
+//! Async versions of the snapshot operations, enabled by the `async` feature.
+//!
+//! All external commands are spawned by `tokio::process`,
+//! other blocking operations(eg. waiting for the lock of a volume)
+//! are moved to the blocking thread pool of tokio;
+//! a rollback is moved there as a whole, see [rollback],
+//! and so is each request to a daemon, see [AsyncDaemonClient].
+//!
+
+use crate::{
+    api::{
+        client::{ack_snapshot, bind_client, send},
+        model::{Req, Resp, MAX_PACKET},
+    },
+    audit,
+    driver::{self, check_output},
+    lock::VolumeLock,
+    logging::{Event, LogLevel},
+    metrics, pin, AckMode, BtmCfg, BtmError, DaemonStatus, Result, SnapMode, SnapStatus,
+};
+use ruc::*;
+use std::time::Instant;
+use tokio::{net::UnixDatagram, process::Command, task, time};
+
+/// Generate a snapshot for the latest state of blockchain
+pub async fn snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    // the lock is held by the daemon in `External` mode
+    if let SnapMode::External = cfg.mode {
+        return request_snapshot(cfg, idx).await;
+    }
+
+    let _lk = lock(cfg, format!("snapshot {}", idx)).await?;
+
+    // sync data to disk before snapshoting
+    blocking(nix::unistd::sync).await?;
+
+    let snaps = get_sorted_snapshots(cfg).await?;
+    if snaps.contains(&idx) {
+        return Err(BtmError::SnapshotExists(idx));
+    }
+
+    alt!(!driver::itv_matched(cfg, idx), return Ok(()));
+    let mut rec = audit::start(cfg, "snapshot", Some(idx));
+    rec.destroyed = destroy(cfg, &driver::outdated(cfg, &snaps)?).await;
+    let cmd = driver::snapshot_cmd(cfg, idx)?;
+    let res = metrics::timed_async(cfg, "snapshot", exec_output(&cmd))
+        .await
+        .map(|_| ());
+    finish(cfg, rec, res).await
+}
+
+/// Rollback the state of blockchain to a specificed height,
+/// see [BtmCfg::rollback](crate::BtmCfg::rollback) for the details.
+///
+/// The hooks, the busy check and the rollback are one sequence,
+/// it is shared with the blocking API and runs in the blocking thread pool.
+pub async fn rollback(cfg: &BtmCfg, idx: Option<i128>, strict: bool) -> Result<()> {
+    let c = cfg.clone();
+    blocking(move || c.rollback(idx, strict)).await?
+}
+
+/// Get snapshot list in 'DESC' order.
+pub async fn get_sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
+    let output = exec_output(&driver::list_cmd(cfg)?).await?;
+    driver::parse_list(cfg, &output)
+}
+
+/// Clean all existing snapshots, except the latest `cap_clean_kept` ones.
+pub async fn clean_snapshots(cfg: &BtmCfg) -> Result<()> {
+    cfg.refuse_external("clean")?;
+    let _lk = lock(cfg, "clean".to_owned()).await?;
+    let to_del = get_sorted_snapshots(cfg)
+        .await?
+        .into_iter()
+        .skip(cfg.cap_clean_kept)
+        .rev()
+        .collect::<Vec<_>>();
+    let mut rec = audit::start(cfg, "clean", None);
+    rec.destroyed = destroy(cfg, &to_del).await;
+    finish(cfg, rec, Ok(())).await
+}
+
+// Write the audit record in the blocking thread pool
+async fn finish(cfg: &BtmCfg, rec: audit::AuditRecord, res: Result<()>) -> Result<()> {
+    let c = cfg.clone();
+    blocking(move || {
+        audit::finish(&c, rec, &res);
+        res
+    })
+    .await?
+}
+
+/// Request the `btm daemon` to create a snapshot,
+/// the async version of the `External` mode client.
+///
+/// See [ClientCfg](crate::ClientCfg) for the timeout and retry policy.
+pub async fn request_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    let mut timed_out = false;
+    let mut attempt = 0;
+
+    loop {
+        let res = match cfg.client.ack {
+            AckMode::Done => request_once(cfg, &Req::Snapshot { idx }).await,
+            AckMode::Queued => request_once(cfg, &Req::Enqueue { idx }).await,
+            AckMode::Sent => {
+                send(&cfg.daemon_socket(), &Req::Enqueue { idx }, None).map(|_| Resp::Ok)
+            }
+        };
+        timed_out |= matches!(res, Err(BtmError::DaemonTimeout));
+
+        match res {
+            Err(BtmError::DaemonUnreachable(_) | BtmError::DaemonTimeout | BtmError::QueueFull)
+                if attempt < cfg.client.retries =>
+            {
+                time::sleep(cfg.client.backoff(attempt)).await;
+                attempt += 1;
+            }
+            res => return res.and_then(|r| ack_snapshot(idx, r, timed_out)),
+        }
+    }
+}
+
+/// The async version of [DaemonClient](crate::DaemonClient),
+/// each request is moved to the blocking thread pool of tokio as a whole,
+/// so that the retry policy of the blocking client is kept.
+///
+/// ```ignore
+/// let cli = btm::aio::AsyncDaemonClient::from(cfg.daemon_client());
+/// let status = cli.snapshot_status(21).await?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct AsyncDaemonClient(crate::DaemonClient);
+
+impl From<crate::DaemonClient> for AsyncDaemonClient {
+    fn from(cli: crate::DaemonClient) -> Self {
+        Self(cli)
+    }
+}
+
+impl AsyncDaemonClient {
+    /// See [DaemonClient::snapshot](crate::DaemonClient::snapshot)
+    pub async fn snapshot(&self, idx: u64) -> Result<()> {
+        self.call(move |c| c.snapshot(idx)).await
+    }
+
+    /// See [DaemonClient::snapshot_status](crate::DaemonClient::snapshot_status)
+    pub async fn snapshot_status(&self, idx: u64) -> Result<Option<SnapStatus>> {
+        self.call(move |c| c.snapshot_status(idx)).await
+    }
+
+    /// See [DaemonClient::list](crate::DaemonClient::list)
+    pub async fn list(&self) -> Result<Vec<u64>> {
+        self.call(|c| c.list()).await
+    }
+
+    /// See [DaemonClient::rollback](crate::DaemonClient::rollback)
+    pub async fn rollback(&self, idx: Option<u64>, strict: bool) -> Result<()> {
+        self.call(move |c| c.rollback(idx, strict)).await
+    }
+
+    /// See [DaemonClient::clean](crate::DaemonClient::clean)
+    pub async fn clean(&self, kept: usize) -> Result<()> {
+        self.call(move |c| c.clean(kept)).await
+    }
+
+    /// See [DaemonClient::status](crate::DaemonClient::status)
+    pub async fn status(&self) -> Result<DaemonStatus> {
+        self.call(|c| c.status()).await
+    }
+
+    /// See [DaemonClient::pin](crate::DaemonClient::pin)
+    pub async fn pin(&self, idx: u64) -> Result<()> {
+        self.call(move |c| c.pin(idx)).await
+    }
+
+    /// See [DaemonClient::unpin](crate::DaemonClient::unpin)
+    pub async fn unpin(&self, idx: u64) -> Result<()> {
+        self.call(move |c| c.unpin(idx)).await
+    }
+
+    /// See [DaemonClient::shutdown](crate::DaemonClient::shutdown)
+    pub async fn shutdown(&self) -> Result<()> {
+        self.call(|c| c.shutdown()).await
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&crate::DaemonClient) -> Result<T> + Send + 'static,
+    {
+        let c = self.0.clone();
+        blocking(move || f(&c)).await?
+    }
+}
+
+async fn request_once(cfg: &BtmCfg, req: &Req) -> Result<Resp> {
+    let cli = bind_client().c(d!())?;
+    cli.set_nonblocking(true).c(d!())?;
+    // `tokio` can not send to an abstract address directly
+    send(&cfg.daemon_socket(), req, Some(&cli))?;
+    let cli = UnixDatagram::from_std(cli).c(d!())?;
+
+    let mut buf = vec![0u8; MAX_PACKET];
+    let n = time::timeout(cfg.client.timeout, cli.recv(&mut buf))
+        .await
+        .map_err(|_| BtmError::DaemonTimeout)?
+        .c(d!())?;
+
+    match Resp::from_bytes(&buf[..n])? {
+        Resp::Busy => Err(BtmError::QueueFull),
+        r => Ok(r),
+    }
+}
+
+async fn exec_output(cmd: &str) -> Result<String> {
+    let res = Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .output()
+        .await
+        .c(d!())?;
+    check_output(cmd, res)
+}
+
+// Failures are logged and omitted,
+// they will be retried in the next round of cleaning,
+// return the heights that have been destroyed
+async fn destroy(cfg: &BtmCfg, heights: &[u64]) -> Vec<u64> {
+    let c = cfg.clone();
+    let hs = heights.to_vec();
+    // destroy nothing if the pin list is unknown
+    let heights = blocking(move || pin::unpinned(&c, &hs))
+        .await
+        .and_then(|r| r);
+    let heights = match heights {
+        Ok(hs) => hs,
+        Err(e) => {
+            Event::new("prune")
+                .volume(&cfg.volume)
+                .fail(LogLevel::Error, &e);
+            return vec![];
+        }
+    };
+    alt!(heights.is_empty(), return heights);
+
+    let start = Instant::now();
+    let cmds = driver::destroy_cmds(cfg, &heights);
+    let mut failed = vec![];
+    for (i, cmd) in cmds.iter().enumerate() {
+        if let Err(e) = exec_output(cmd).await {
+            driver::destroy_failed(cfg, &heights, cmds.len(), i, &e);
+            failed.push(i);
+        }
+    }
+    metrics::observe(cfg, "prune", start, failed.is_empty());
+    driver::destroy_done(cfg, &heights, failed.len(), start);
+    driver::destroyed(&heights, cmds.len(), &failed)
+}
+
+async fn lock(cfg: &BtmCfg, op: String) -> Result<VolumeLock> {
+    let volume = cfg.volume.clone();
+    let timeout = cfg.lock_timeout;
+    blocking(move || VolumeLock::acquire(&volume, &op, timeout)).await?
+}
+
+#[inline(always)]
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .c(d!())
+        .map_err(BtmError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::model::socket_addr, DaemonClient};
+    use std::{
+        os::unix::net::UnixDatagram as StdDatagram,
+        process,
+        thread::{self, JoinHandle},
+    };
+    use tokio::runtime::{Builder, Runtime};
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    // Answer `n` requests like a daemon holding the snapshots 35 and 25
+    fn fake_daemon(name: &str, n: usize) -> JoinHandle<Vec<Req>> {
+        let s = StdDatagram::bind_addr(&socket_addr(name).unwrap()).unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET];
+            let mut reqs = vec![];
+            for _ in 0..n {
+                let (len, peer) = s.recv_from(&mut buf).unwrap();
+                let (req, _) = Req::from_bytes(&buf[..len]).unwrap();
+                let resp = match req {
+                    Req::Query { .. } => Resp::Progress {
+                        status: Some(SnapStatus::Done),
+                    },
+                    Req::List => Resp::Snapshots { list: vec![35, 25] },
+                    Req::Pin { idx: 15 } => Resp::Error {
+                        msg: "specified height does not exist: 15".to_owned(),
+                    },
+                    _ => Resp::Ok,
+                };
+                s.send_to_addr(&resp.to_bytes(), &peer).unwrap();
+                reqs.push(req);
+            }
+            reqs
+        })
+    }
+
+    #[test]
+    fn request_snapshot() {
+        let name = format!("btm-test-{}-aio-snapshot", process::id());
+        let daemon = fake_daemon(&name, 1);
+        let mut cfg = BtmCfg::new("tank/test", Some("external")).unwrap();
+        cfg.socket = Some(name);
+
+        runtime().block_on(snapshot(&cfg, 25)).unwrap();
+        let reqs = daemon.join().unwrap();
+        assert!(matches!(reqs[..], [Req::Snapshot { idx: 25 }]));
+    }
+
+    #[test]
+    fn request_the_daemon() {
+        let name = format!("btm-test-{}-aio-client", process::id());
+        let daemon = fake_daemon(&name, 6);
+        let cli = AsyncDaemonClient::from(DaemonClient::new(&name).retries(0));
+
+        runtime().block_on(async {
+            assert_eq!(
+                Some(SnapStatus::Done),
+                cli.snapshot_status(25).await.unwrap()
+            );
+            assert_eq!(vec![35, 25], cli.list().await.unwrap());
+            cli.rollback(Some(25), true).await.unwrap();
+            cli.clean(1).await.unwrap();
+            cli.pin(35).await.unwrap();
+            assert!(matches!(
+                cli.pin(15).await,
+                Err(BtmError::DaemonFailure(e)) if e.contains("15")
+            ));
+        });
+        let reqs = daemon.join().unwrap();
+        assert!(matches!(
+            reqs[2..],
+            [
+                Req::Rollback {
+                    idx: Some(25),
+                    strict: true
+                },
+                Req::Clean { kept: 1 },
+                Req::Pin { idx: 35 },
+                Req::Pin { idx: 15 }
+            ]
+        ));
+    }
+}
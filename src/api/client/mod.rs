@@ -1,27 +1,344 @@
-use crate::api::model::{Req, Resp, SERVER_US_ADDR};
-use ruc::{uau::UauSock, *};
-
-#[inline(always)]
-pub(crate) fn request_snapshot(idx: u64) -> Result<()> {
-    // set receive timeout to 500ms, aka 0.5second
-    let cli = UauSock::gen(Some(500)).c(d!())?;
-    cli.send(
-        &Req::new(idx).to_bytes(),
-        &pnk!(UauSock::addr_to_sock(SERVER_US_ADDR)),
-    )
-    .c(d!())?;
-
-    // try at most 20 times, aka 10 seconds
-    for _ in 0..20 {
-        if let Ok(resp) = cli.recvonly_128() {
-            let r = serde_json::from_slice::<Resp>(&resp).c(d!())?;
-            if r.success() && r.idx() == idx {
-                return Ok(());
-            } else {
-                return Err(eg!("snapshot failed"));
+//!
+//! Client of `btm daemon ...`
+//!
+
+use crate::{
+    api::model::{socket_addr, DaemonStatus, Req, Resp, MAX_PACKET},
+    BtmError, Result, SnapStatus,
+};
+use ruc::*;
+use std::{
+    io::ErrorKind,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// How to talk to the daemon, used in the `External` mode
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ClientCfg {
+    /// How long to wait for each response, default to 3 seconds
+    pub timeout: Duration,
+    /// How many times to retry if the daemon is unreachable, timed out or busy, default to 3;
+    /// only requests that can be repeated safely are retried,
+    /// eg. snapshots and queries, but not rollbacks
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each next retry, default to 100ms
+    pub backoff: Duration,
+    /// When does a snapshot request return, default to `AckMode::Done`
+    pub ack: AckMode,
+}
+
+/// When does a snapshot request of the `External` mode return
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AckMode {
+    /// After the snapshot has been created
+    #[default]
+    Done,
+    /// After the height has been queued by the daemon,
+    /// the result can be queried by [DaemonClient::snapshot_status]
+    Queued,
+    /// Right after the request has been sent, the daemon will not respond
+    Sent,
+}
+
+impl Default for ClientCfg {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(3),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            ack: AckMode::Done,
+        }
+    }
+}
+
+impl ClientCfg {
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+/// A client of a running `btm daemon`
+#[derive(Clone, Debug)]
+pub struct DaemonClient {
+    socket: String,
+    cfg: ClientCfg,
+}
+
+impl DaemonClient {
+    /// Create a client with the default [ClientCfg],
+    /// `socket` is the address of the daemon,
+    /// see [BtmCfg::daemon_socket](crate::BtmCfg::daemon_socket)
+    pub fn new(socket: &str) -> Self {
+        Self {
+            socket: socket.to_owned(),
+            cfg: ClientCfg::default(),
+        }
+    }
+
+    /// Replace the whole config of the client
+    pub fn config(mut self, cfg: ClientCfg) -> Self {
+        self.cfg = cfg;
+        self
+    }
+
+    /// How long to wait for each response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.cfg.timeout = timeout;
+        self
+    }
+
+    /// How many times to retry
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.cfg.retries = retries;
+        self
+    }
+
+    /// Request the daemon to create a snapshot,
+    /// see [AckMode] for when it returns;
+    /// waiting for the snapshot, [BtmError::Coalesced] is returned
+    /// if the daemon has created a later one instead
+    pub fn snapshot(&self, idx: u64) -> Result<()> {
+        match self.cfg.ack {
+            AckMode::Done => self.snapshot_done(idx),
+            AckMode::Queued => self.request(&Req::Enqueue { idx }).and_then(expect_ok),
+            AckMode::Sent => self.retry(|| send(&self.socket, &Req::Enqueue { idx }, None)),
+        }
+    }
+
+    /// Get the status of a height queued by the daemon,
+    /// `None` if it is unknown or too old
+    pub fn snapshot_status(&self, idx: u64) -> Result<Option<SnapStatus>> {
+        match self.request(&Req::Query { idx })? {
+            Resp::Progress { status } => Ok(status),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    fn snapshot_done(&self, idx: u64) -> Result<()> {
+        let req = Req::Snapshot { idx };
+        let mut timed_out = false;
+        self.retry(|| {
+            let res = self.request_once(&req);
+            timed_out |= matches!(res, Err(BtmError::DaemonTimeout));
+            res
+        })
+        .and_then(|r| ack_snapshot(idx, r, timed_out))
+    }
+
+    /// Get snapshot list in 'DESC' order
+    pub fn list(&self) -> Result<Vec<u64>> {
+        match self.request(&Req::List)? {
+            Resp::Snapshots { list } => Ok(list),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Request the daemon to rollback,
+    /// `None` means the latest snapshot
+    pub fn rollback(&self, idx: Option<u64>, strict: bool) -> Result<()> {
+        self.request(&Req::Rollback { idx, strict })
+            .and_then(expect_ok)
+    }
+
+    /// Clean all snapshots except the latest `kept` ones
+    pub fn clean(&self, kept: usize) -> Result<()> {
+        self.request(&Req::Clean { kept }).and_then(expect_ok)
+    }
+
+    /// Get the status of the daemon
+    pub fn status(&self) -> Result<DaemonStatus> {
+        match self.request(&Req::Status)? {
+            Resp::Status(s) => Ok(*s),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Protect a snapshot from being cleaned up
+    pub fn pin(&self, idx: u64) -> Result<()> {
+        self.request(&Req::Pin { idx }).and_then(expect_ok)
+    }
+
+    /// Cancel the protection of a snapshot
+    pub fn unpin(&self, idx: u64) -> Result<()> {
+        self.request(&Req::Unpin { idx }).and_then(expect_ok)
+    }
+
+    /// Request the daemon to exit
+    pub fn shutdown(&self) -> Result<()> {
+        self.request(&Req::Shutdown).and_then(expect_ok)
+    }
+
+    // a rollback timed out may be still running,
+    // so requests changing the volume are never retried
+    fn request(&self, req: &Req) -> Result<Resp> {
+        if req.idempotent() {
+            self.retry(|| self.request_once(req))
+        } else {
+            self.request_once(req)
+        }
+    }
+
+    fn request_once(&self, req: &Req) -> Result<Resp> {
+        let cli = bind_client().c(d!())?;
+        cli.set_read_timeout(Some(self.cfg.timeout)).c(d!())?;
+        send(&self.socket, req, Some(&cli))?;
+
+        let mut buf = vec![0u8; MAX_PACKET];
+        let n = cli.recv(&mut buf).map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => BtmError::DaemonTimeout,
+            _ => eg!(e).into(),
+        })?;
+
+        match Resp::from_bytes(&buf[..n])? {
+            Resp::Busy => Err(BtmError::QueueFull),
+            r => Ok(r),
+        }
+    }
+
+    fn retry<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(
+                    BtmError::DaemonUnreachable(_) | BtmError::DaemonTimeout | BtmError::QueueFull,
+                ) if attempt < self.cfg.retries => {
+                    thread::sleep(self.cfg.backoff(attempt));
+                    attempt += 1;
+                }
+                res => return res,
             }
         }
     }
+}
+
+/// Send without a `cli` if no response is wanted,
+/// the daemon will not reply to an unbound(anonymous) socket.
+pub(crate) fn send(socket: &str, req: &Req, cli: Option<&UnixDatagram>) -> Result<()> {
+    let unbound;
+    let cli = match cli {
+        Some(c) => c,
+        None => {
+            unbound = UnixDatagram::unbound().c(d!())?;
+            &unbound
+        }
+    };
+    let server = socket_addr(socket)?;
+    cli.send_to_addr(&req.to_bytes(), &server)
+        .map(|_| ())
+        .map_err(|e| match e.kind() {
+            // eg. a socket file of another user
+            ErrorKind::PermissionDenied => BtmError::PermissionDenied(e.to_string()),
+            _ => BtmError::DaemonUnreachable(e.to_string()),
+        })
+}
 
-    Err(eg!("snapshot failed"))
+/// A timed out request may have been done by the daemon,
+/// in which case the retried one will find the snapshot existing.
+pub(crate) fn ack_snapshot(idx: u64, r: Resp, timed_out: bool) -> Result<()> {
+    match r {
+        // no snapshot is needed for this height
+        Resp::Skipped => Ok(()),
+        Resp::Coalesced { into } => Err(BtmError::Coalesced { idx, into }),
+        Resp::Exists if timed_out => Ok(()),
+        Resp::Exists => Err(BtmError::SnapshotExists(idx)),
+        r => expect_ok(r),
+    }
+}
+
+/// A bound address is needed to receive the response
+pub(crate) fn bind_client() -> ruc::Result<UnixDatagram> {
+    static CNT: AtomicU64 = AtomicU64::new(0);
+    let name = format!(
+        "btm-cli-{}-{}",
+        std::process::id(),
+        CNT.fetch_add(1, Ordering::Relaxed)
+    );
+    let addr = SocketAddr::from_abstract_name(name.as_bytes()).c(d!())?;
+    UnixDatagram::bind_addr(&addr).c(d!())
+}
+
+pub(crate) fn expect_ok(r: Resp) -> Result<()> {
+    match r {
+        Resp::Ok => Ok(()),
+        r => Err(unexpected(r)),
+    }
+}
+
+pub(crate) fn unexpected(r: Resp) -> BtmError {
+    match r {
+        Resp::Error { msg } => BtmError::DaemonFailure(msg),
+        Resp::Busy => BtmError::QueueFull,
+        Resp::Denied => BtmError::PermissionDenied("not an allowed peer of the daemon".to_owned()),
+        r => BtmError::DaemonFailure(format!("unexpected response: {:?}", r)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn ack_an_existing_snapshot() {
+        assert!(ack_snapshot(25, Resp::Ok, false).is_ok());
+        assert!(ack_snapshot(25, Resp::Skipped, false).is_ok());
+        assert!(matches!(
+            ack_snapshot(25, Resp::Coalesced { into: 35 }, false),
+            Err(BtmError::Coalesced { idx: 25, into: 35 })
+        ));
+        assert!(ack_snapshot(25, Resp::Exists, true).is_ok());
+        assert!(matches!(
+            ack_snapshot(25, Resp::Exists, false),
+            Err(BtmError::SnapshotExists(25))
+        ));
+        assert!(matches!(
+            ack_snapshot(
+                25,
+                Resp::Error {
+                    msg: "x".to_owned()
+                },
+                true
+            ),
+            Err(BtmError::DaemonFailure(_))
+        ));
+    }
+
+    #[test]
+    fn report_denied_requests() {
+        assert!(matches!(
+            unexpected(Resp::Denied),
+            BtmError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            expect_ok(Resp::from_bytes(&Resp::Denied.to_bytes()).unwrap()),
+            Err(BtmError::PermissionDenied(_))
+        ));
+    }
+
+    #[test]
+    fn retry_idempotent_requests_only() {
+        let cfg = ClientCfg {
+            backoff: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let cli = DaemonClient::new(&format!("btm-test-none-{}", std::process::id())).config(cfg);
+
+        let start = Instant::now();
+        assert!(matches!(
+            cli.rollback(None, false),
+            Err(BtmError::DaemonUnreachable(_))
+        ));
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        let start = Instant::now();
+        assert!(matches!(cli.list(), Err(BtmError::DaemonUnreachable(_))));
+        assert!(Duration::from_millis(200 + 400 + 800) <= start.elapsed());
+    }
 }
@@ -3,5 +3,5 @@
 //!
 
 pub(crate) mod client;
-mod model;
+pub(crate) mod model;
 pub mod server;
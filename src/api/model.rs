@@ -1,52 +1,354 @@
 //!
 //! # Data Model of API
 //!
+//! Each packet is a JSON object like `{"v":1,"body":{...}}`,
+//! `v` is the version of the protocol.
+//!
+//! Packets without a version, aka `{"idx":N}`,
+//! are treated as snapshot requests of the first generation,
+//! and will be replied with a `{"idx":N,"success":true}` packet.
+//!
 
+use crate::{
+    driver,
+    logging::{Event, LogLevel},
+    replica, BtmCfg, SnapAlgo, SnapMode, SnapStatus,
+};
 use ruc::*;
 use serde::{Deserialize, Serialize};
+use std::{
+    os::{linux::net::SocketAddrExt, unix::net::SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Current version of the wire protocol
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// Big enough for a list of `CAP_MAX` snapshots
+pub(crate) const MAX_PACKET: usize = 256 * 1024;
+
+/// The fixed address of the daemon of the first generation,
+/// also listened on if [BtmCfg::socket] is not set, so that old clients keep working
+pub(crate) const LEGACY_SOCKET: &str = "b1ce842e9f6e96d36287c8cfece722d";
+
+/// The default address of the daemon of a volume,
+/// so that each volume can have its own daemon
+pub(crate) fn default_socket(volume: &str) -> String {
+    format!("btm@{}", volume.trim_end_matches('/').replace('/', "%"))
+}
 
-/// SEE: [UAU](ruc::uau::UauSock)
-pub(crate) const SERVER_US_ADDR: &[u8] = b"b1ce842e9f6e96d36287c8cfece722d";
+/// An absolute path means a socket file,
+/// others are names in the abstract namespace
+pub(crate) fn socket_addr(socket: &str) -> Result<SocketAddr> {
+    if socket.starts_with('/') {
+        SocketAddr::from_pathname(socket).c(d!())
+    } else {
+        SocketAddr::from_abstract_name(socket.as_bytes()).c(d!())
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct Req {
-    idx: u64,
+struct Packet<T> {
+    v: u32,
+    body: T,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Req {
+    Snapshot {
+        idx: u64,
+    },
+    /// Queue the height, and respond at once
+    Enqueue {
+        idx: u64,
+    },
+    /// Status of a queued height
+    Query {
+        idx: u64,
+    },
+    List,
+    Rollback {
+        idx: Option<u64>,
+        strict: bool,
+    },
+    Clean {
+        kept: usize,
+    },
+    Status,
+    Pin {
+        idx: u64,
+    },
+    Unpin {
+        idx: u64,
+    },
+    Shutdown,
 }
 
 impl Req {
-    pub fn new(idx: u64) -> Self {
-        Self { idx }
+    /// Name of the operation, used in log events
+    pub(crate) fn op(&self) -> &'static str {
+        match self {
+            Req::Snapshot { .. } | Req::Enqueue { .. } => "snapshot",
+            Req::Query { .. } => "query",
+            Req::List => "list",
+            Req::Rollback { .. } => "rollback",
+            Req::Clean { .. } => "clean",
+            Req::Status => "status",
+            Req::Pin { .. } => "pin",
+            Req::Unpin { .. } => "unpin",
+            Req::Shutdown => "shutdown",
+        }
     }
 
-    pub fn idx(&self) -> u64 {
-        self.idx
+    /// Whether it can be retried safely,
+    /// a timed out request may have been done by the daemon
+    pub(crate) fn idempotent(&self) -> bool {
+        matches!(
+            self,
+            Req::Snapshot { .. }
+                | Req::Enqueue { .. }
+                | Req::Query { .. }
+                | Req::List
+                | Req::Status
+        )
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        pnk!(serde_json::to_vec(self))
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let p = Packet {
+            v: PROTOCOL_VERSION,
+            body: self,
+        };
+        pnk!(serde_json::to_vec(&p))
+    }
+
+    /// Also return `true` if it is a legacy packet
+    pub(crate) fn from_bytes(b: &[u8]) -> Result<(Self, bool)> {
+        if let Ok(p) = serde_json::from_slice::<Packet<Req>>(b) {
+            if p.v > PROTOCOL_VERSION {
+                return Err(eg!("unsupported protocol version: {}", p.v));
+            }
+            return Ok((p.body, false));
+        }
+
+        serde_json::from_slice::<LegacyReq>(b)
+            .c(d!("invalid request"))
+            .map(|r| (Req::Snapshot { idx: r.idx }, true))
     }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct Resp {
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Resp {
+    /// Done, for a snapshot request, the snapshot has been created
+    Ok,
+    /// No snapshot is needed for the requested height, filtered by `itv`
+    Skipped,
+    /// The requested height has been coalesced into a later one
+    Coalesced {
+        into: u64,
+    },
+    Snapshots {
+        list: Vec<u64>,
+    },
+    Progress {
+        status: Option<SnapStatus>,
+    },
+    /// The queue is full, try again later
+    Busy,
+    /// The snapshot of the requested height had been created before
+    Exists,
+    Status(Box<DaemonStatus>),
+    /// The peer is not allowed, see [Peers](crate::Peers)
+    Denied,
+    Error {
+        msg: String,
+    },
+}
+
+impl Resp {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let p = Packet {
+            v: PROTOCOL_VERSION,
+            body: self,
+        };
+        pnk!(serde_json::to_vec(&p))
+    }
+
+    pub(crate) fn from_bytes(b: &[u8]) -> Result<Self> {
+        serde_json::from_slice::<Packet<Resp>>(b)
+            .c(d!("invalid response"))
+            .map(|p| p.body)
+    }
+}
+
+/// Status of a running `btm daemon`, or of a volume inspected directly
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DaemonStatus {
+    /// Version of the wire protocol
+    pub version: u32,
+    /// The volume managed by the daemon
+    pub volume: String,
+    /// Zfs or Btrfs
+    pub mode: SnapMode,
+    /// Fair or Fade
+    pub algo: SnapAlgo,
+    /// The interval between adjacent snapshots
+    pub itv: u64,
+    /// The maximum number of snapshots
+    pub cap: u64,
+    /// Height of the latest snapshot
+    pub latest: Option<u64>,
+    /// Number of existing snapshots
+    pub count: usize,
+    /// Heights of pinned snapshots
+    pub pinned: Vec<u64>,
+    /// Seconds since the daemon started, `None` if inspected directly
+    #[serde(default)]
+    pub uptime: Option<u64>,
+    /// Seconds since the latest snapshot was created
+    #[serde(default)]
+    pub latest_age: Option<u64>,
+    /// Free space of the pool or the filesystem in bytes
+    #[serde(default)]
+    pub free: Option<u64>,
+    /// The last error of the daemon, eg. a failed snapshot
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Unix timestamp of the last error
+    #[serde(default)]
+    pub last_error_at: Option<u64>,
+    /// The highest height requested by the node
+    #[serde(default)]
+    pub requested: Option<u64>,
+    /// Why the daemon is stale, `None` if it is not, see [StaleCfg](crate::StaleCfg)
+    #[serde(default)]
+    pub stale: Option<String>,
+    /// Height of the last snapshot replicated, see [ReplicaCfg](crate::ReplicaCfg)
+    #[serde(default)]
+    pub replicated: Option<u64>,
+}
+
+impl DaemonStatus {
+    /// `snaps` should be in 'DESC' order,
+    /// the age and the free space are left empty if they can not be queried
+    pub(crate) fn collect(cfg: &BtmCfg, snaps: &[u64]) -> crate::Result<Self> {
+        let latest = snaps.first().copied();
+        let latest_age = latest.and_then(|h| {
+            let created = driver::created_at(cfg, h).map_err(|e| {
+                Event::new("status")
+                    .volume(&cfg.volume)
+                    .height(h)
+                    .fail(LogLevel::Debug, &e)
+            });
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+            Some(now.saturating_sub(created.ok()?))
+        });
+        let free = driver::free_space(cfg)
+            .map_err(|e| {
+                Event::new("status")
+                    .volume(&cfg.volume)
+                    .fail(LogLevel::Debug, &e)
+            })
+            .ok();
+
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            volume: cfg.volume.clone(),
+            mode: cfg.mode,
+            algo: cfg.algo,
+            itv: cfg.itv,
+            cap: cfg.cap,
+            latest,
+            count: snaps.len(),
+            pinned: cfg.get_pinned()?,
+            uptime: None,
+            latest_age,
+            free,
+            last_error: None,
+            last_error_at: None,
+            requested: None,
+            stale: None,
+            replicated: replica::replicated(cfg),
+        })
+    }
+}
+
+/// Snapshot requests of the first generation
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct LegacyReq {
+    idx: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct LegacyResp {
     idx: u64,
     success: bool,
 }
 
-impl Resp {
-    pub fn new(idx: u64, success: bool) -> Self {
+impl LegacyResp {
+    pub(crate) fn new(idx: u64, success: bool) -> Self {
         Self { idx, success }
     }
 
-    pub fn idx(&self) -> u64 {
-        self.idx
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        pnk!(serde_json::to_vec(self))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn success(&self) -> bool {
-        self.success
+    #[test]
+    fn parse_versioned_requests() {
+        let b = Req::Rollback {
+            idx: Some(25),
+            strict: true,
+        }
+        .to_bytes();
+        assert!(matches!(
+            Req::from_bytes(&b),
+            Ok((
+                Req::Rollback {
+                    idx: Some(25),
+                    strict: true
+                },
+                false
+            ))
+        ));
+        assert!(matches!(
+            Req::from_bytes(br#"{"v":1,"body":{"op":"list"}}"#),
+            Ok((Req::List, false))
+        ));
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        pnk!(serde_json::to_vec(self))
+    #[test]
+    fn parse_legacy_requests() {
+        assert!(matches!(
+            Req::from_bytes(br#"{"idx":25}"#),
+            Ok((Req::Snapshot { idx: 25 }, true))
+        ));
+        assert_eq!(
+            br#"{"idx":25,"success":true}"#.to_vec(),
+            LegacyResp::new(25, true).to_bytes()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_requests() {
+        assert!(Req::from_bytes(br#"{"v":2,"body":{"op":"list"}}"#).is_err());
+        assert!(Req::from_bytes(br#"{"v":1,"body":{"op":"format"}}"#).is_err());
+        assert!(Req::from_bytes(b"snapshot 25").is_err());
+    }
+
+    #[test]
+    fn parse_responses() {
+        let b = Resp::Snapshots { list: vec![25, 15] }.to_bytes();
+        assert!(matches!(
+            Resp::from_bytes(&b),
+            Ok(Resp::Snapshots { list }) if list == [25, 15]
+        ));
+        assert!(Resp::from_bytes(br#"{"idx":25,"success":true}"#).is_err());
     }
 }
@@ -0,0 +1,36 @@
+//!
+//! Authorization of the peers of the daemon
+//!
+//! The credentials of a peer are attached by the kernel(`SCM_CREDENTIALS`),
+//! so they can not be forged by the peer itself.
+//!
+//! Only these credentials are checked; supplementary groups are not,
+//! they are not attached by the kernel, and looking them up by the pid
+//! may race with the peer exiting and the pid being reused.
+//!
+
+use nix::{sys::socket::UnixCredentials, unistd::geteuid};
+use serde::{Deserialize, Serialize};
+
+/// Peers that are allowed to send requests to the daemon,
+/// `root` and the owner of the daemon are always allowed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Peers {
+    /// Allowed users
+    pub uids: Vec<u32>,
+    /// Allowed groups, only matched against the primary gid of the peer
+    pub gids: Vec<u32>,
+}
+
+impl Peers {
+    pub(crate) fn allowed(&self, cred: &UnixCredentials) -> bool {
+        let uid = cred.uid();
+        if 0 == uid || geteuid().as_raw() == uid || self.uids.contains(&uid) {
+            return true;
+        }
+
+        self.gids.contains(&cred.gid())
+    }
+}
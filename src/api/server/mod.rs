@@ -2,22 +2,622 @@
 //! Logic of `btm daemon ...`
 //!
 
+mod auth;
+mod systemd;
+mod waiter;
+
+pub use auth::Peers;
+
 use crate::{
-    api::model::{Req, Resp, SERVER_US_ADDR},
-    BtmCfg,
+    api::model::{socket_addr, DaemonStatus, LegacyResp, Req, Resp, LEGACY_SOCKET, MAX_PACKET},
+    audit,
+    index::SnapIndex,
+    logging::{self, Event, LogLevel},
+    metrics,
+    replica::Replicator,
+    stale::Tracker,
+    BtmCfg, BtmError, Result, SnapMode, SnapStatus, SnapWorker,
+};
+use nix::{
+    cmsg_space,
+    errno::Errno,
+    libc::sa_family_t,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::socket::{
+        recvmsg, setsockopt, sockopt::PassCred, ControlMessageOwned, MsgFlags, SockaddrLike,
+        UnixAddr, UnixCredentials,
+    },
+};
+use ruc::*;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    flag,
 };
-use ruc::{uau::UauSock, *};
+use std::{
+    fs,
+    io::IoSliceMut,
+    mem,
+    os::{
+        fd::{AsFd, AsRawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use waiter::Waiters;
+
+/// The watchdog allows a rollback or a clean to block the daemon for this long
+const LONG_OP_TIMEOUT: Duration = Duration::from_secs(30 * 60);
 
-/// Run `btm daemon ...` server
+/// Run `btm daemon ...` server,
+/// listening on the address returned by [BtmCfg::daemon_socket],
+/// and also on the address of the first generation if [BtmCfg::socket] is not set;
+/// requests from peers not in [BtmCfg::peers] will be rejected.
+///
+/// Snapshot requests are queued and processed by a [SnapWorker],
+/// a `busy` response is returned if the queue is full.
+///
+/// On `SIGTERM`, `SIGINT` or a shutdown request, the snapshot in progress
+/// will be finished, queued heights and new requests are rejected,
+/// and then `Ok(())` is returned; a second signal kills the daemon at once.
+///
+/// Prometheus metrics are served on [BtmCfg::metrics] if it is set.
+///
+/// If started by systemd, the daemon reports its readiness and the latest snapshot,
+/// pings the watchdog, and accepts a datagram socket passed by the socket activation.
 pub fn run_daemon(cfg: BtmCfg) -> Result<()> {
-    let s = pnk!(UauSock::new(SERVER_US_ADDR, None));
-    loop {
-        if let Ok((msg, peer)) = s.recv_128() {
-            if let Ok(r) = info!(serde_json::from_slice::<Req>(&msg)) {
-                let success = info!(cfg.snapshot(r.idx())).is_ok();
-                s.send(&Resp::new(r.idx(), success).to_bytes(), &peer)
-                    .c(d!())?;
+    serve(cfg, None)
+}
+
+/// Like [run_daemon], but the config is loaded from a file,
+/// see [BtmCfg::from_file] for the format.
+///
+/// The file will be reloaded on `SIGHUP`, queued requests are not affected;
+/// changes of `volume`, `mode`, `socket`, `queue_size`, `metrics` and `replica` need a restart.
+pub fn run_daemon_with_config(path: &Path) -> Result<()> {
+    serve(load_cfg(path, None)?, Some(path))
+}
+
+// A missing `mode` is guessed, or inherited from the running daemon,
+// the daemon can not work in the `External` mode
+fn load_cfg(path: &Path, mode: Option<SnapMode>) -> Result<BtmCfg> {
+    let (mut cfg, has_mode) = BtmCfg::load_file(path)?;
+    if !has_mode {
+        cfg.mode = match mode {
+            Some(m) => m,
+            None => SnapMode::guess(&cfg.volume)?,
+        };
+    } else if let SnapMode::External = cfg.mode {
+        return Err(BtmError::InvalidConfig(
+            "the daemon can not work in the `External` mode".to_owned(),
+        ));
+    }
+    Ok(cfg)
+}
+
+fn serve(cfg: BtmCfg, cfg_path: Option<&Path>) -> Result<()> {
+    cfg.log.apply();
+
+    let socket = cfg.daemon_socket();
+    let (s, activated) = match systemd::listen_socket() {
+        Some(s) => (s, true),
+        None => (bind(&socket)?, false),
+    };
+    let mut socks = vec![Arc::new(s)];
+    // clients of the first generation only know the fixed address
+    if !activated && cfg.socket.is_none() {
+        match bind(LEGACY_SOCKET) {
+            Ok(s) => socks.push(Arc::new(s)),
+            // eg. held by the daemon of another volume
+            Err(e) => Event::new("daemon")
+                .volume(&cfg.volume)
+                .warn(format!("the legacy address is not listened on: {}", e)),
+        }
+    }
+    for s in socks.iter() {
+        setsockopt(s.as_ref(), PassCred, &true).c(d!())?;
+    }
+
+    // wake up periodically to process signals
+    let wakeup = Duration::from_secs(1);
+
+    let reload = Arc::new(AtomicBool::new(false));
+    flag::register(SIGHUP, Arc::clone(&reload)).c(d!())?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for sig in [SIGTERM, SIGINT] {
+        // exit at once if a previous signal has been received
+        flag::register_conditional_shutdown(sig, 1, Arc::clone(&stop)).c(d!())?;
+        flag::register(sig, Arc::clone(&stop)).c(d!())?;
+    }
+
+    if let Some(addr) = cfg.metrics.as_deref() {
+        metrics::serve(addr)?;
+    }
+
+    let mut d = Daemon::new(cfg)?;
+    let mut buf = vec![0u8; MAX_PACKET];
+
+    // the volume has been checked by loading the index
+    d.publish_status();
+    systemd::notify("READY=1");
+    let mut wd = systemd::Watchdog::new();
+    Event::new("daemon").volume(&d.cfg.volume).info(format!(
+        "listening on {}{}",
+        alt!(activated, "the activated socket", &socket),
+        alt!(1 < socks.len(), " and the legacy address", "")
+    ));
+
+    while !stop.load(Ordering::Relaxed) {
+        if let Some(wd) = wd.as_mut() {
+            wd.ping();
+        }
+        d.stale.check(&d.cfg);
+
+        if reload.swap(false, Ordering::Relaxed) {
+            if let Some(path) = cfg_path {
+                let ev = Event::new("reload").volume(&d.cfg.volume);
+                match d.reload(path) {
+                    Ok(()) => ev.info(format!("reloaded from {}", path.display())),
+                    Err(e) => ev.fail(LogLevel::Error, &e),
+                }
+            }
+        }
+
+        let Incoming {
+            len: n,
+            peer,
+            cred,
+            sock,
+        } = match recv(&socks, &mut buf, wakeup) {
+            Ok(Some(r)) => r,
+            Ok(None) => continue,
+            Err(e) => {
+                Event::new("recv").error(e.get_lowest_msg());
+                continue;
+            }
+        };
+
+        let authorized = cred
+            .as_ref()
+            .map(|c| d.cfg.peers.allowed(c))
+            .unwrap_or(false);
+        if !authorized {
+            Event::new("auth").warn(format!(
+                "unauthorized request rejected, peer: {}",
+                cred.map(|c| format!("pid {}, uid {}, gid {}", c.pid(), c.uid(), c.gid()))
+                    .unwrap_or_else(|| "unknown".to_owned())
+            ));
+            if let Some(peer) = peer {
+                reply(&sock, &Resp::Denied.to_bytes(), &peer);
             }
+            continue;
         }
+        let req = Req::from_bytes(&buf[..n]).inspect_err(|e| {
+            Event::new("request").warn(e.get_lowest_msg());
+        });
+
+        let (resp, shutdown) = match req {
+            // replied by the worker once it has been processed
+            Ok((Req::Snapshot { idx }, legacy)) => {
+                d.snapshot(idx, peer, legacy, sock);
+                continue;
+            }
+            Ok((r, _)) => {
+                let shutdown = matches!(r, Req::Shutdown);
+                // hooks and destroying snapshots may take a while
+                let _ext = wd
+                    .as_mut()
+                    .filter(|_| matches!(r, Req::Rollback { .. } | Req::Clean { .. }))
+                    .map(|wd| wd.extend(LONG_OP_TIMEOUT));
+                // mutating operations are audited as the peer
+                let resp = match cred {
+                    Some(c) => audit::with_caller(c.pid() as u32, c.uid(), || d.handle(r)),
+                    None => d.handle(r),
+                };
+                (resp.to_bytes(), shutdown)
+            }
+            Err(e) => {
+                let resp = Resp::Error {
+                    msg: e.get_lowest_msg(),
+                };
+                (resp.to_bytes(), false)
+            }
+        };
+
+        // anonymous peers can not be replied
+        if let Some(peer) = peer {
+            reply(&sock, &resp, &peer);
+        }
+
+        if shutdown {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    Event::new("shutdown")
+        .volume(&d.cfg.volume)
+        .info("waiting for the snapshot in progress");
+    systemd::notify("STOPPING=1");
+    let worker = thread::spawn(move || d.worker.shutdown());
+
+    // keep answering until the worker exits, so that clients need not wait for a timeout
+    while !worker.is_finished() {
+        if let Some(wd) = wd.as_mut() {
+            wd.ping();
+        }
+        if let Ok(Some(Incoming {
+            len,
+            peer: Some(peer),
+            sock,
+            ..
+        })) = recv(&socks, &mut buf, wakeup)
+        {
+            let resp = match Req::from_bytes(&buf[..len]) {
+                Ok((Req::Snapshot { idx }, true)) => LegacyResp::new(idx, false).to_bytes(),
+                _ => Resp::Error {
+                    msg: "the daemon is shutting down".to_owned(),
+                }
+                .to_bytes(),
+            };
+            reply(&sock, &resp, &peer);
+        }
+    }
+    if worker.join().is_err() {
+        Event::new("worker").error("the worker panicked");
+    }
+
+    nix::unistd::sync();
+    // the socket file of the socket activation belongs to systemd
+    if !activated && Path::new(&socket).is_absolute() {
+        if let Err(e) = fs::remove_file(&socket) {
+            Event::new("shutdown").warn(e);
+        }
+    }
+    Event::new("shutdown").info("exited");
+    Ok(())
+}
+
+fn reply(s: &UnixDatagram, resp: &[u8], peer: &SocketAddr) {
+    if let Err(e) = s.send_to_addr(resp, peer) {
+        Event::new("reply").warn(e);
+    }
+}
+
+struct Daemon {
+    cfg: BtmCfg,
+    index: Arc<SnapIndex>,
+    waiters: Arc<Waiters>,
+    worker: SnapWorker,
+    stale: Arc<Tracker>,
+    // only held to be dropped after the worker,
+    // so that the last snapshot can be replicated
+    _replica: Option<Arc<Replicator>>,
+    started: Instant,
+}
+
+impl Daemon {
+    fn new(cfg: BtmCfg) -> Result<Self> {
+        let index = Arc::new(SnapIndex::load(&cfg)?);
+        let waiters = Arc::new(Waiters::default());
+        let latest = index.sorted()?.first().copied();
+        let stale = Arc::new(Tracker::new(&cfg, latest));
+        let replica = if cfg.replica.enabled() {
+            let r = Arc::new(Replicator::spawn(cfg.clone())?);
+            // catch up with snapshots created while the daemon was down
+            if let Some(h) = latest {
+                r.push(h);
+            }
+            Some(r)
+        } else {
+            None
+        };
+
+        let w = Arc::clone(&waiters);
+        let t = Arc::clone(&stale);
+        let r = replica.clone();
+        let worker = SnapWorker::spawn(
+            cfg.clone(),
+            Some(Box::new(move |idx, st| {
+                if let SnapStatus::Done = st {
+                    t.done(idx);
+                    if let Some(r) = r.as_ref() {
+                        r.push(idx);
+                    }
+                    systemd::status(Some(idx));
+                }
+                w.reply(idx, Ok(st))
+            })),
+            Some(Arc::clone(&index)),
+        )?;
+
+        Ok(Self {
+            cfg,
+            index,
+            waiters,
+            worker,
+            stale,
+            _replica: replica,
+            started: Instant::now(),
+        })
+    }
+
+    fn reload(&mut self, path: &Path) -> Result<()> {
+        let cfg = load_cfg(path, Some(self.cfg.mode))?;
+
+        // the queue has been created with the old size
+        if cfg.volume != self.cfg.volume
+            || cfg.mode != self.cfg.mode
+            || cfg.socket != self.cfg.socket
+            || cfg.queue_size != self.cfg.queue_size
+            || cfg.metrics != self.cfg.metrics
+            || cfg.replica != self.cfg.replica
+        {
+            return Err(BtmError::InvalidConfig(
+                "`volume`, `mode`, `socket`, `queue_size`, `metrics` and `replica` can not be changed without a restart"
+                    .to_owned(),
+            ));
+        }
+
+        cfg.log.apply();
+        self.worker.set_cfg(cfg.clone());
+        self.cfg = cfg;
+        Ok(())
+    }
+
+    fn snapshot(&self, idx: u64, peer: Option<SocketAddr>, legacy: bool, sock: Arc<UnixDatagram>) {
+        self.stale.requested(idx);
+        if let Some(peer) = peer {
+            self.waiters.add(idx, peer, legacy, sock);
+        }
+        match self.worker.submit(idx) {
+            Ok(()) => {
+                // eg. filtered by `itv`, or processed before
+                if let Some(st) = self.worker.status(idx) {
+                    self.waiters.reply(idx, Ok(&st));
+                }
+            }
+            Err(e) => self.waiters.reply(idx, Err(&e)),
+        }
+    }
+
+    fn handle(&self, req: Req) -> Resp {
+        let cfg = &self.cfg;
+        let start = Instant::now();
+        let ev = Event::new(req.op()).volume(&cfg.volume).height(match req {
+            Req::Rollback { idx, .. } => idx,
+            Req::Pin { idx } | Req::Unpin { idx } => Some(idx),
+            _ => None,
+        });
+        let changing = matches!(
+            req,
+            Req::Rollback { .. } | Req::Clean { .. } | Req::Pin { .. } | Req::Unpin { .. }
+        );
+
+        let res = match req {
+            // waiting for the result is handled by `Daemon::snapshot`
+            Req::Snapshot { idx } | Req::Enqueue { idx } => {
+                self.stale.requested(idx);
+                self.worker.submit(idx).map(|_| Resp::Ok)
+            }
+            Req::Query { idx } => Ok(Resp::Progress {
+                status: self.worker.status(idx),
+            }),
+            Req::List => self.index.sorted().map(|list| Resp::Snapshots { list }),
+            Req::Rollback { idx, strict } => {
+                // no stale height can be captured in the middle
+                let _g = self.worker.hold();
+                let res = cfg.rollback_to(idx.map(|i| i as i128), strict);
+                self.refresh_index();
+                // even if the `post_rollback` hook has failed
+                if let Ok((target, _)) = res {
+                    self.worker.rolled_back(target);
+                    let latest = self.index.sorted().ok().and_then(|s| s.first().copied());
+                    self.stale.rolled_back(latest);
+                }
+                self.publish_status();
+                res.and_then(|(_, post)| post).map(|_| Resp::Ok)
+            }
+            Req::Clean { kept } => {
+                let mut cfg = cfg.clone();
+                cfg.cap_clean_kept = kept;
+                let res = cfg.clean_snapshots();
+                self.refresh_index();
+                self.publish_status();
+                res.map(|_| Resp::Ok)
+            }
+            Req::Status => self.status().map(|s| Resp::Status(Box::new(s))),
+            Req::Pin { idx } => cfg.pin(idx).map(|_| Resp::Ok),
+            Req::Unpin { idx } => cfg.unpin(idx).map(|_| Resp::Ok),
+            Req::Shutdown => Ok(Resp::Ok),
+        };
+
+        match res {
+            Ok(r) => {
+                let ev = ev.since(start);
+                alt!(changing, ev.info("done"), ev.debug("done"));
+                r
+            }
+            Err(BtmError::QueueFull) => {
+                ev.warn("the queue is full");
+                Resp::Busy
+            }
+            Err(e) => {
+                let msg = match &e {
+                    BtmError::Other(e) => e.get_lowest_msg(),
+                    e => e.to_string(),
+                };
+                ev.since(start).fail(LogLevel::Error, &e);
+                Resp::Error { msg }
+            }
+        }
+    }
+
+    fn refresh_index(&self) {
+        if let Err(e) = self.index.refresh() {
+            Event::new("index")
+                .volume(&self.cfg.volume)
+                .fail(LogLevel::Warn, &e);
+        }
+    }
+
+    fn publish_status(&self) {
+        match self.index.sorted() {
+            Ok(snaps) => {
+                systemd::status(snaps.first().copied());
+                metrics::set_snapshots(&self.cfg, &snaps);
+            }
+            Err(e) => Event::new("index")
+                .volume(&self.cfg.volume)
+                .fail(LogLevel::Warn, &e),
+        }
+        metrics::refresh_space_used(&self.cfg);
+    }
+
+    fn status(&self) -> Result<DaemonStatus> {
+        let mut st = DaemonStatus::collect(&self.cfg, &self.index.sorted()?)?;
+        st.uptime = Some(self.started.elapsed().as_secs());
+        if let Some((ts, msg)) = logging::last_error() {
+            st.last_error_at = Some(ts);
+            st.last_error = Some(msg);
+        }
+        (st.requested, st.stale) = self.stale.get();
+        Ok(st)
+    }
+}
+
+// A packet received into the buffer
+struct Incoming {
+    len: usize,
+    // the address of its sender
+    peer: Option<SocketAddr>,
+    // the credentials of its sender
+    cred: Option<UnixCredentials>,
+    // the socket it is received on, to be replied from
+    sock: Arc<UnixDatagram>,
+}
+
+// Receive a packet from any of the sockets,
+// `None` if timed out or interrupted by a signal
+fn recv(
+    socks: &[Arc<UnixDatagram>],
+    buf: &mut [u8],
+    timeout: Duration,
+) -> ruc::Result<Option<Incoming>> {
+    let mut fds = socks
+        .iter()
+        .map(|s| PollFd::new(s.as_fd(), PollFlags::POLLIN))
+        .collect::<Vec<_>>();
+    match poll(
+        &mut fds,
+        PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX),
+    ) {
+        Ok(_) => {}
+        Err(Errno::EINTR) => return Ok(None),
+        Err(e) => return Err(eg!(e)),
+    }
+    let s = match fds.iter().position(|fd| fd.any().unwrap_or(false)) {
+        Some(i) => &socks[i],
+        None => return Ok(None),
+    };
+
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = cmsg_space!(UnixCredentials);
+    let msg = match recvmsg::<UnixAddr>(
+        s.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_DONTWAIT,
+    ) {
+        Ok(m) => m,
+        Err(Errno::EAGAIN | Errno::EINTR) => return Ok(None),
+        Err(e) => return Err(eg!(e)),
+    };
+
+    let cred = msg.cmsgs().c(d!())?.find_map(|c| match c {
+        ControlMessageOwned::ScmCredentials(c) => Some(c),
+        _ => None,
+    });
+
+    // the address of an unnamed peer is empty, and can not be parsed
+    let peer = msg
+        .address
+        .filter(|a| a.len() as usize > mem::size_of::<sa_family_t>())
+        .and_then(|a| {
+            if let Some(name) = a.as_abstract() {
+                SocketAddr::from_abstract_name(name).ok()
+            } else {
+                a.path().and_then(|p| SocketAddr::from_pathname(p).ok())
+            }
+        });
+
+    Ok(Some(Incoming {
+        len: msg.bytes,
+        peer,
+        cred,
+        sock: Arc::clone(s),
+    }))
+}
+
+fn bind(socket: &str) -> Result<UnixDatagram> {
+    let path = Path::new(socket);
+    if path.is_absolute() && path.exists() {
+        // a socket file left by a dead daemon can be reused
+        if UnixDatagram::unbound().c(d!())?.connect(path).is_ok() {
+            return Err(BtmError::InvalidConfig(format!(
+                "another daemon is listening on {}",
+                socket
+            )));
+        }
+        fs::remove_file(path).c(d!())?;
+    }
+    UnixDatagram::bind_addr(&socket_addr(socket)?)
+        .c(d!())
+        .map_err(BtmError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn reply_legacy_requests() {
+        // not the real legacy address, which may be held by a running daemon
+        let name = |s: &str| format!("btm-test-{}-{}", process::id(), s);
+        let socks = [name("main"), name("legacy")].map(|n| Arc::new(bind(&n).unwrap()));
+        for s in socks.iter() {
+            setsockopt(s.as_ref(), PassCred, &true).unwrap();
+        }
+
+        let cli = UnixDatagram::bind_addr(&socket_addr(&name("cli")).unwrap()).unwrap();
+        cli.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        cli.send_to_addr(br#"{"idx":25}"#, &socket_addr(&name("legacy")).unwrap())
+            .unwrap();
+
+        let mut buf = vec![0u8; MAX_PACKET];
+        let inc = recv(&socks, &mut buf, Duration::from_secs(3))
+            .unwrap()
+            .unwrap();
+        assert!(Arc::ptr_eq(&socks[1], &inc.sock));
+        assert!(inc.cred.is_some());
+        let (req, legacy) = Req::from_bytes(&buf[..inc.len]).unwrap();
+        assert!(legacy);
+        assert!(matches!(req, Req::Snapshot { idx: 25 }));
+
+        let waiters = Waiters::default();
+        waiters.add(25, inc.peer.unwrap(), legacy, inc.sock);
+        waiters.reply(25, Ok(&SnapStatus::Done));
+
+        let mut resp = [0u8; 128];
+        let (n, from) = cli.recv_from(&mut resp).unwrap();
+        assert_eq!(br#"{"idx":25,"success":true}"#, &resp[..n]);
+        assert_eq!(Some(name("legacy").as_bytes()), from.as_abstract_name());
     }
 }
@@ -0,0 +1,153 @@
+//!
+//! A native implementation of the `sd_notify` protocol
+//! and the socket activation of systemd,
+//! all of them are no-ops if the daemon is not started by systemd.
+//!
+
+use crate::logging::Event;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{getsockopt, sockopt::SockType, SockType as Type},
+};
+use ruc::*;
+use std::{
+    env,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process,
+    time::{Duration, Instant},
+};
+
+/// The first fd passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Send a state, eg. `READY=1`, to the service manager
+pub(super) fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) if !p.is_empty() => p,
+        _ => return,
+    };
+    if let Err(e) = send(&path, state) {
+        Event::new("sd_notify").warn(e.get_lowest_msg());
+    }
+}
+
+fn send(path: &str, state: &str) -> ruc::Result<()> {
+    // a leading '@' means an abstract name
+    let addr = if let Some(name) = path.strip_prefix('@') {
+        SocketAddr::from_abstract_name(name.as_bytes()).c(d!())?
+    } else {
+        SocketAddr::from_pathname(path).c(d!())?
+    };
+    UnixDatagram::unbound()
+        .c(d!())?
+        .send_to_addr(state.as_bytes(), &addr)
+        .c(d!())
+        .map(|_| ())
+}
+
+/// Publish the latest snapshot height in `systemctl status`
+pub(super) fn status(latest: Option<u64>) {
+    let latest = latest.map(|h| h.to_string());
+    notify(&format!(
+        "STATUS=latest snapshot: {}",
+        latest.as_deref().unwrap_or("none")
+    ));
+}
+
+/// The socket passed by systemd, if the daemon is socket activated
+pub(super) fn listen_socket() -> Option<UnixDatagram> {
+    let pid = env::var("LISTEN_PID").ok()?;
+    let fds = env::var("LISTEN_FDS").ok()?;
+    if pid.parse::<u32>().ok()? != process::id() {
+        return None;
+    }
+
+    // only the first one is used
+    if fds.parse::<u32>().ok()? < 1 {
+        return None;
+    }
+
+    // Safety: systemd passes the fds starting from 3,
+    // and nothing else in this process takes the ownership of it
+    let s = unsafe { UnixDatagram::from_raw_fd(LISTEN_FDS_START) };
+    if !matches!(getsockopt(&s, SockType), Ok(Type::Datagram)) {
+        Event::new("socket_activation").warn("not a datagram socket, ignored");
+        return None;
+    }
+
+    // the fd should not leak into the commands spawned by btm;
+    // the variables are left as they are, changing the environment is unsafe
+    // once other threads are running, and child processes will ignore them,
+    // `LISTEN_PID` does not match their pids
+    if let Err(e) = fcntl(LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        Event::new("socket_activation").warn(e);
+    }
+
+    Some(s)
+}
+
+/// Send `WATCHDOG=1` at half of the interval required by systemd,
+/// [Watchdog::ping] is called from the serve loop,
+/// so that a wedged loop is detected and restarted
+pub(super) struct Watchdog {
+    // required by systemd
+    itv: Duration,
+    last: Instant,
+}
+
+impl Watchdog {
+    /// `None` if the watchdog is not enabled for this process
+    pub(super) fn new() -> Option<Self> {
+        let itv = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|us| us.parse::<u64>().ok())
+            .filter(|_| {
+                // may be set for another process
+                env::var("WATCHDOG_PID")
+                    .ok()
+                    .map(|pid| pid.parse::<u32>().ok() == Some(process::id()))
+                    .unwrap_or(true)
+            })
+            .map(Duration::from_micros)?;
+        notify("WATCHDOG=1");
+        Some(Self {
+            itv,
+            last: Instant::now(),
+        })
+    }
+
+    /// Ping if half of the interval has passed
+    pub(super) fn ping(&mut self) {
+        if self.itv / 2 <= self.last.elapsed() {
+            notify("WATCHDOG=1");
+            self.last = Instant::now();
+        }
+    }
+
+    /// Raise the interval to `limit` for an operation blocking the loop,
+    /// eg. a rollback waiting for its hooks,
+    /// it is restored once the returned guard is dropped
+    pub(super) fn extend(&mut self, limit: Duration) -> Extended<'_> {
+        notify(&format!(
+            "WATCHDOG_USEC={}",
+            self.itv.max(limit).as_micros()
+        ));
+        notify("WATCHDOG=1");
+        Extended(self)
+    }
+}
+
+/// See [Watchdog::extend]
+pub(super) struct Extended<'a>(&'a mut Watchdog);
+
+impl Drop for Extended<'_> {
+    fn drop(&mut self) {
+        notify(&format!("WATCHDOG_USEC={}", self.0.itv.as_micros()));
+        notify("WATCHDOG=1");
+        self.0.last = Instant::now();
+    }
+}
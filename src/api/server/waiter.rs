@@ -0,0 +1,69 @@
+//!
+//! Peers waiting for their snapshot requests
+//!
+//! Snapshots are created by the worker in the background,
+//! and the peers are replied once their heights have been processed.
+//!
+
+use crate::{
+    api::model::{LegacyResp, Resp},
+    logging::Event,
+    BtmError, SnapStatus,
+};
+use std::{
+    collections::BTreeMap,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    result::Result as StdResult,
+    sync::{Arc, Mutex},
+};
+
+struct Waiter {
+    peer: SocketAddr,
+    legacy: bool,
+    // replied from the socket that the request was received on
+    sock: Arc<UnixDatagram>,
+}
+
+#[derive(Default)]
+pub(crate) struct Waiters {
+    waiters: Mutex<BTreeMap<u64, Vec<Waiter>>>,
+}
+
+impl Waiters {
+    pub(crate) fn add(&self, idx: u64, peer: SocketAddr, legacy: bool, sock: Arc<UnixDatagram>) {
+        self.waiters
+            .lock()
+            .unwrap()
+            .entry(idx)
+            .or_default()
+            .push(Waiter { peer, legacy, sock });
+    }
+
+    /// Reply all peers waiting for this height,
+    /// each peer will only be replied once
+    pub(crate) fn reply(&self, idx: u64, res: StdResult<&SnapStatus, &BtmError>) {
+        let resp = match res {
+            Ok(SnapStatus::Pending) => return,
+            Ok(SnapStatus::Done) => Resp::Ok,
+            Ok(SnapStatus::Skipped) => Resp::Skipped,
+            Ok(SnapStatus::Coalesced(into)) => Resp::Coalesced { into: *into },
+            Ok(SnapStatus::Exists) => Resp::Exists,
+            Ok(SnapStatus::Failed(msg)) => Resp::Error { msg: msg.clone() },
+            Err(BtmError::QueueFull) => Resp::Busy,
+            Err(e) => Resp::Error { msg: e.to_string() },
+        };
+
+        let waiters = self.waiters.lock().unwrap().remove(&idx);
+        for w in waiters.into_iter().flatten() {
+            let resp = if w.legacy {
+                // heights filtered by `itv` also succeeded in the first generation
+                LegacyResp::new(idx, matches!(resp, Resp::Ok | Resp::Skipped)).to_bytes()
+            } else {
+                resp.to_bytes()
+            };
+            if let Err(e) = w.sock.send_to_addr(&resp, &w.peer) {
+                Event::new("reply").height(idx).warn(e);
+            }
+        }
+    }
+}
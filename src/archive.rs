@@ -0,0 +1,642 @@
+//!
+//! # Portable archives of snapshots
+//!
+//! An archive is a zstd-compressed tar of the contents of a snapshot,
+//! unlike `zfs send` or `btrfs send` streams, it can be restored into a volume of any mode.
+//!
+//! Files of the snapshot are stored under `data/`,
+//! followed by `btm-archive.json`, see [ArchiveManifest],
+//! so that the snapshot is read only once while being archived;
+//! nothing is moved into the volume until all files match their SHA-256 digests.
+//!
+
+use crate::{
+    audit, driver,
+    logging::{Event, LogLevel},
+    BtmCfg, BtmError, Result, SnapMode,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tar::{Builder, EntryType, Header};
+
+/// Version of the format of archives
+const ARCHIVE_VERSION: u32 = 1;
+
+const MANIFEST: &str = "btm-archive.json";
+
+const DATA: &str = "data";
+
+/// Files are unpacked here at first, it is under the mount point of the volume
+const STAGING: &str = ".btm-restore";
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Contents of an archive
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ArchiveManifest {
+    /// Version of the format
+    pub version: u32,
+    /// The archived volume
+    pub volume: String,
+    /// Mode of the archived volume, it does not restrict the restore
+    pub mode: SnapMode,
+    /// Height of the snapshot
+    pub height: u64,
+    /// Unix timestamp of the creation of the snapshot, if known
+    pub created: Option<u64>,
+    /// Unix timestamp of the archive
+    pub ts: u64,
+    /// Regular files and symlinks, in the order of the archive,
+    /// directories are not listed
+    pub files: Vec<ArchiveFile>,
+}
+
+/// A file in an archive
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ArchiveFile {
+    /// Path relative to the root of the snapshot
+    pub path: String,
+    /// Size in bytes, `0` for a symlink
+    pub size: u64,
+    /// Hex SHA-256 digest of the contents, `None` for a symlink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Target of a symlink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+impl ArchiveManifest {
+    /// Total size of all files in bytes, before compression
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+/// Pack the contents of the snapshot `idx` into `file`
+pub(crate) fn archive(cfg: &BtmCfg, idx: u64, file: &Path) -> Result<ArchiveManifest> {
+    let start = Instant::now();
+
+    // write to a temporary file at first, and then rename it
+    let mut tmp = OsString::from(file);
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let res = File::create(&tmp)
+        .c(d!())
+        .map_err(From::from)
+        .and_then(|f| write(cfg, idx, BufWriter::new(f)))
+        .and_then(|(m, w)| {
+            w.into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|f| f.sync_all())
+                .and_then(|_| fs::rename(&tmp, file))
+                .c(d!())?;
+            Ok(m)
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    let m = res?;
+
+    Event::new("archive")
+        .volume(&cfg.volume)
+        .height(idx)
+        .since(start)
+        .info(format!(
+            "{} files, {} bytes archived into {}",
+            m.files.len(),
+            m.size(),
+            file.display()
+        ));
+    Ok(m)
+}
+
+/// Write the archive of the snapshot `idx` into `w`, and give it back
+pub(crate) fn write<W: Write>(cfg: &BtmCfg, idx: u64, w: W) -> Result<(ArchiveManifest, W)> {
+    if !driver::sorted_snapshots(cfg)?.contains(&idx) {
+        return Err(BtmError::HeightNotFound(idx));
+    }
+    let root = driver::snapshot_path(cfg, idx)?;
+    if !root.is_dir() {
+        return Err(eg!("{} is not readable", root.display()).into());
+    }
+
+    let mut m = ArchiveManifest {
+        version: ARCHIVE_VERSION,
+        volume: cfg.volume.clone(),
+        mode: cfg.mode,
+        height: idx,
+        created: driver::created_at(cfg, idx).ok(),
+        ts: now(),
+        files: vec![],
+    };
+    let w = pack(cfg, &root, w, &mut m)?;
+    Ok((m, w))
+}
+
+fn pack<W: Write>(cfg: &BtmCfg, root: &Path, w: W, m: &mut ArchiveManifest) -> Result<W> {
+    let mut enc = zstd::Encoder::new(w, ZSTD_LEVEL).c(d!())?;
+    enc.include_checksum(true).c(d!())?;
+
+    let mut builder = Builder::new(enc);
+    builder.follow_symlinks(false);
+    walk(root, root, &mut |path, rel, meta| {
+        let name = Path::new(DATA).join(rel);
+        let ft = meta.file_type();
+        if ft.is_dir() {
+            builder.append_dir(&name, path).c(d!())?;
+        } else if ft.is_symlink() {
+            builder.append_path_with_name(path, &name).c(d!())?;
+            m.files.push(symlink(path, rel)?);
+        } else if ft.is_file() {
+            let mut header = Header::new_gnu();
+            header.set_metadata(meta);
+            let mut r = Digesting::new(File::open(path).c(d!())?);
+            builder.append_data(&mut header, &name, &mut r).c(d!())?;
+            m.files.push(ArchiveFile {
+                path: rel.to_owned(),
+                size: meta.len(),
+                sha256: Some(r.hex()),
+                link: None,
+            });
+        } else {
+            Event::new("archive")
+                .volume(&cfg.volume)
+                .height(m.height)
+                .warn(format!("not a regular file, skipped: {}", path.display()));
+        }
+        Ok(())
+    })?;
+
+    let manifest = serde_json::to_vec_pretty(m).c(d!())?;
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(m.ts);
+    builder
+        .append_data(&mut header, MANIFEST, manifest.as_slice())
+        .c(d!())?;
+
+    builder
+        .into_inner()
+        .and_then(|enc| enc.finish())
+        .c(d!())
+        .map_err(From::from)
+}
+
+/// Content digest of the snapshot at `root`,
+/// the SHA-256 of the file list of its archive in JSON,
+/// every file is read through, return the digest and the number of files
+pub(crate) fn digest(root: &Path) -> Result<(String, usize)> {
+    let mut files = vec![];
+    walk(root, root, &mut |path, rel, meta| {
+        let ft = meta.file_type();
+        if ft.is_symlink() {
+            files.push(symlink(path, rel)?);
+        } else if ft.is_file() {
+            let mut r = Digesting::new(File::open(path).c(d!())?);
+            let size =
+                io::copy(&mut r, &mut io::sink()).c(d!("failed to read {}", path.display()))?;
+            files.push(ArchiveFile {
+                path: rel.to_owned(),
+                size,
+                sha256: Some(r.hex()),
+                link: None,
+            });
+        }
+        Ok(())
+    })?;
+    let list = serde_json::to_vec(&files).c(d!())?;
+    Ok((format!("{:x}", Sha256::digest(list)), files.len()))
+}
+
+// Visit entries under `dir` sorted by name, symlinks are not followed,
+// so that the same snapshot produces the same archive
+fn walk(
+    root: &Path,
+    dir: &Path,
+    f: &mut dyn FnMut(&Path, &str, &fs::Metadata) -> Result<()>,
+) -> Result<()> {
+    let mut paths = fs::read_dir(dir)
+        .c(d!())?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .c(d!())?;
+    paths.sort();
+
+    for path in paths {
+        let rel = path
+            .strip_prefix(root)
+            .c(d!())?
+            .to_str()
+            .c(d!("not a UTF-8 path: {}", path.display()))?;
+        let meta = fs::symlink_metadata(&path).c(d!())?;
+        f(&path, rel, &meta)?;
+        if meta.is_dir() {
+            walk(root, &path, f)?;
+        }
+    }
+    Ok(())
+}
+
+fn symlink(path: &Path, rel: &str) -> Result<ArchiveFile> {
+    let link = fs::read_link(path).c(d!())?;
+    Ok(ArchiveFile {
+        path: rel.to_owned(),
+        size: 0,
+        sha256: None,
+        link: Some(link.to_string_lossy().into_owned()),
+    })
+}
+
+/// Verify `file` and restore it into the volume as the snapshot of its height
+pub(crate) fn restore(cfg: &BtmCfg, file: &Path) -> Result<ArchiveManifest> {
+    let f = File::open(file).c(d!())?;
+    restore_from(cfg, "restore-archive", &file.display().to_string(), f)
+}
+
+/// Restore an archive read from `r`, `src` describes where it comes from
+pub(crate) fn restore_from<R: Read>(
+    cfg: &BtmCfg,
+    op: &'static str,
+    src: &str,
+    r: R,
+) -> Result<ArchiveManifest> {
+    let start = Instant::now();
+    let mut rec = audit::start(cfg, op, None);
+    let res = restore_into(cfg, src, r);
+    rec.resolved = res.as_ref().ok().map(|m| m.height);
+    audit::finish(cfg, rec, &res);
+
+    let ev = Event::new(op).volume(&cfg.volume).since(start);
+    match &res {
+        Ok(m) => ev.height(m.height).info(format!(
+            "{} files, {} bytes restored from {}",
+            m.files.len(),
+            m.size(),
+            src
+        )),
+        Err(e) => ev.fail(LogLevel::Error, e),
+    }
+    res
+}
+
+fn restore_into<R: Read>(cfg: &BtmCfg, src: &str, r: R) -> Result<ArchiveManifest> {
+    driver::create(cfg)?;
+    if !driver::sorted_snapshots(cfg)?.is_empty() {
+        return Err(BtmError::InvalidConfig(format!(
+            "{} has snapshots, archives can only be restored into a new volume",
+            cfg.volume
+        )));
+    }
+    let mp = driver::mountpoint(cfg)?.c(d!("{} is not mounted", &cfg.volume))?;
+
+    // left by an interrupted restore
+    let staging = mp.join(STAGING);
+    if staging.exists() {
+        fs::remove_dir_all(&staging).c(d!())?;
+    }
+    // `.zfs` is listed if the `snapdir` property is `visible`
+    let used = fs::read_dir(&mp)
+        .c(d!())?
+        .filter_map(|e| e.ok())
+        .any(|e| e.file_name() != ".zfs");
+    if used {
+        return Err(BtmError::InvalidConfig(format!(
+            "{} is not empty, archives can only be restored into a new volume",
+            mp.display()
+        )));
+    }
+
+    fs::create_dir(&staging).c(d!())?;
+    let res = unpack(src, r, &staging).and_then(|m| {
+        for e in fs::read_dir(staging.join(DATA)).c(d!())? {
+            let e = e.c(d!())?;
+            fs::rename(e.path(), mp.join(e.file_name())).c(d!())?;
+        }
+        fs::remove_dir_all(&staging).c(d!())?;
+        Ok(m)
+    });
+    if res.is_err() {
+        let _ = fs::remove_dir_all(&staging);
+    }
+    let m = res?;
+
+    // sync data to disk before snapshoting,
+    // the height may not match `itv`, so the snapshot is created directly
+    nix::unistd::sync();
+    driver::exec_output(&driver::snapshot_cmd(cfg, m.height)?)?;
+    Ok(m)
+}
+
+// Unpack all files into `dir/data`, and verify them against the manifest,
+// the type and the parents of each entry are checked before it is written,
+// so that nothing can be placed out of `dir`, eg. through an unpacked symlink
+fn unpack<R: Read>(src: &str, r: R, dir: &Path) -> Result<ArchiveManifest> {
+    let dec = zstd::Decoder::new(r).c(d!())?;
+    let mut ar = tar::Archive::new(dec);
+    ar.set_preserve_permissions(true);
+    ar.set_preserve_ownerships(nix::unistd::geteuid().is_root());
+    fs::create_dir(dir.join(DATA)).c(d!())?;
+
+    let mut manifest: Option<ArchiveManifest> = None;
+    let mut found = BTreeMap::new();
+    for entry in ar.entries().c(d!())? {
+        let mut entry = entry.c(d!())?;
+        let path = entry.path().c(d!())?.into_owned();
+
+        if path == Path::new(MANIFEST) {
+            let mut buf = vec![];
+            entry.read_to_end(&mut buf).c(d!())?;
+            let m = serde_json::from_slice(&buf).map_err(|e| invalid(src, e))?;
+            manifest = Some(m);
+            continue;
+        }
+
+        let rel = path
+            .strip_prefix(DATA)
+            .ok()
+            .filter(|r| r.components().all(|c| matches!(c, Component::Normal(_))))
+            .filter(|r| !r.as_os_str().is_empty())
+            .ok_or_else(|| invalid(src, format!("unexpected entry: {}", path.display())))?
+            .to_owned();
+
+        let kind = entry.header().entry_type();
+        if !(kind.is_dir() || kind.is_symlink() || kind.is_file()) {
+            return Err(invalid(
+                src,
+                format!("unsupported entry type {:?}: {}", kind, path.display()),
+            ));
+        }
+        // a parent unpacked as a symlink may point anywhere
+        let mut parent = dir.join(DATA);
+        for c in rel.parent().into_iter().flat_map(|p| p.components()) {
+            parent.push(c);
+            match fs::symlink_metadata(&parent) {
+                Ok(meta) if !meta.is_dir() => {
+                    return Err(invalid(
+                        src,
+                        format!("not a directory in the path: {}", path.display()),
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(eg!(e).into()),
+            }
+        }
+
+        let link = entry
+            .link_name()
+            .c(d!())?
+            .map(|l| l.to_string_lossy().into_owned());
+        if !entry.unpack_in(dir).c(d!())? {
+            return Err(invalid(
+                src,
+                format!("unexpected entry: {}", path.display()),
+            ));
+        }
+        let dst = dir.join(&path);
+
+        let f = match kind {
+            k if k.is_dir() => continue,
+            EntryType::Symlink => ArchiveFile {
+                path: String::new(),
+                size: 0,
+                sha256: None,
+                link,
+            },
+            _ => {
+                let mut r = Digesting::new(File::open(&dst).c(d!())?);
+                let size = io::copy(&mut r, &mut io::sink()).c(d!())?;
+                ArchiveFile {
+                    path: String::new(),
+                    size,
+                    sha256: Some(r.hex()),
+                    link: None,
+                }
+            }
+        };
+        let rel = rel.to_string_lossy().into_owned();
+        found.insert(rel.clone(), ArchiveFile { path: rel, ..f });
+    }
+
+    let m = manifest.ok_or_else(|| invalid(src, "the manifest is missing"))?;
+    if m.version > ARCHIVE_VERSION {
+        return Err(invalid(
+            src,
+            format!("unsupported archive version: {}", m.version),
+        ));
+    }
+    for f in m.files.iter() {
+        match found.remove(&f.path) {
+            Some(g) if g == *f => {}
+            Some(_) => return Err(invalid(src, format!("checksum mismatch: {}", f.path))),
+            None => return Err(invalid(src, format!("missing file: {}", f.path))),
+        }
+    }
+    if let Some(p) = found.keys().next() {
+        return Err(invalid(src, format!("not in the manifest: {}", p)));
+    }
+
+    Ok(m)
+}
+
+// Calculate the SHA-256 digest of the data read through it
+struct Digesting<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Digesting<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn hex(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn invalid(src: &str, msg: impl ToString) -> BtmError {
+    BtmError::InvalidArchive(format!("{}: {}", src, msg.to_string()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink as ln, process};
+
+    fn tmp(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("btm-test-{}-archive-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manifest(files: &[(&str, &[u8])]) -> ArchiveManifest {
+        ArchiveManifest {
+            version: ARCHIVE_VERSION,
+            volume: "tank/test".to_owned(),
+            mode: SnapMode::Zfs,
+            height: 25,
+            created: None,
+            ts: now(),
+            files: files
+                .iter()
+                .map(|(path, data)| ArchiveFile {
+                    path: (*path).to_owned(),
+                    size: data.len() as u64,
+                    sha256: Some(format!("{:x}", Sha256::digest(data))),
+                    link: None,
+                })
+                .collect(),
+        }
+    }
+
+    // Files are given by their paths in the archive
+    fn build(m: &ArchiveManifest, files: &[(&str, &[u8])]) -> Vec<u8> {
+        build_with(m, &[], files)
+    }
+
+    // Links of the given types are placed before files
+    fn build_with(
+        m: &ArchiveManifest,
+        links: &[(&str, EntryType, &str)],
+        files: &[(&str, &[u8])],
+    ) -> Vec<u8> {
+        let header = |kind| {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header
+        };
+        let manifest = serde_json::to_vec(m).unwrap();
+        let enc = zstd::Encoder::new(vec![], ZSTD_LEVEL).unwrap();
+        let mut builder = Builder::new(enc);
+        for (name, kind, target) in links.iter().copied() {
+            let mut header = header(kind);
+            header.set_size(0);
+            builder.append_link(&mut header, name, target).unwrap();
+        }
+        for (name, data) in files.iter().copied().chain([(MANIFEST, &manifest[..])]) {
+            let mut header = header(EntryType::Regular);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn unpack_err(ar: &[u8], name: &str) -> String {
+        match unpack("test", ar, &tmp(name)) {
+            Err(BtmError::InvalidArchive(e)) => e,
+            r => panic!("unexpected result: {:?}", r.map(|m| m.files)),
+        }
+    }
+
+    #[test]
+    fn unpack_what_is_packed() {
+        let src = tmp("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        fs::write(src.join("sub/b.txt"), "world").unwrap();
+        ln("a.txt", src.join("link")).unwrap();
+
+        let cfg = BtmCfg::new("tank/test", Some("zfs")).unwrap();
+        let mut m = manifest(&[]);
+        let ar = pack(&cfg, &src, vec![], &mut m).unwrap();
+        assert_eq!(3, m.files.len());
+
+        let dst = tmp("dst");
+        let n = unpack("test", &ar[..], &dst).unwrap();
+        assert_eq!(m.files, n.files);
+        let data = dst.join(DATA);
+        assert_eq!("world", fs::read_to_string(data.join("sub/b.txt")).unwrap());
+        assert_eq!(
+            Path::new("a.txt"),
+            fs::read_link(data.join("link")).unwrap()
+        );
+        assert_eq!(digest(&src).unwrap(), digest(&data).unwrap());
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(dst).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_archives() {
+        let m = manifest(&[("a.txt", b"hello")]);
+
+        let ar = build(&m, &[("data/a.txt", b"hellO")]);
+        assert!(unpack_err(&ar, "mismatch").contains("checksum mismatch: a.txt"));
+
+        let ar = build(&m, &[]);
+        assert!(unpack_err(&ar, "missing").contains("missing file: a.txt"));
+
+        let ar = build(&m, &[("data/a.txt", b"hello"), ("data/b.txt", b"")]);
+        assert!(unpack_err(&ar, "extra").contains("not in the manifest: b.txt"));
+
+        let ar = build(&m, &[("data/a.txt", b"hello"), ("other/b.txt", b"")]);
+        assert!(unpack_err(&ar, "outside").contains("unexpected entry: other/b.txt"));
+
+        let mut n = m.clone();
+        n.version = ARCHIVE_VERSION + 1;
+        let ar = build(&n, &[("data/a.txt", b"hello")]);
+        assert!(unpack_err(&ar, "version").contains("unsupported archive version"));
+
+        // a file written through a symlink unpacked before it
+        let out = tmp("target");
+        let target = out.to_str().unwrap();
+        let ar = build_with(
+            &m,
+            &[("data/sub", EntryType::Symlink, target)],
+            &[("data/sub/a.txt", b"hello")],
+        );
+        assert!(unpack_err(&ar, "symlink").contains("not a directory in the path: data/sub"));
+        assert!(!out.join("a.txt").exists());
+
+        // nothing but regular files, directories and symlinks is created
+        let ar = build_with(&m, &[("data/a.txt", EntryType::Link, target)], &[]);
+        let dir = tmp("hardlink");
+        assert!(matches!(
+            unpack("test", &ar[..], &dir),
+            Err(BtmError::InvalidArchive(e)) if e.contains("unsupported entry type")
+        ));
+        assert!(!dir.join("data/a.txt").exists());
+
+        for name in [
+            "mismatch", "missing", "extra", "outside", "version", "target", "symlink", "hardlink",
+        ] {
+            fs::remove_dir_all(tmp(name)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//!
+//! # Audit log
+//!
+//! Every mutating operation on a volume is appended to a JSON-lines file
+//! in the state directory, so that "who rolled back what and when" can be answered.
+//!
+//! The file is rotated once it grows too large,
+//! only the latest `AUDIT_KEPT` rotated files are kept.
+//!
+
+use crate::{
+    logging::{Event, LogLevel},
+    state::{self, STATE_DIR},
+    BtmCfg, BtmError, Result,
+};
+use nix::fcntl::{Flock, FlockArg};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Rotate the audit log once it exceeds this size
+const AUDIT_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How many rotated files will be kept
+const AUDIT_KEPT: usize = 4;
+
+thread_local! {
+    // `(pid, uid)` of the peer whose request is being handled by the daemon
+    static CALLER: Cell<Option<(u32, u32)>> = const { Cell::new(None) };
+}
+
+/// A mutating operation recorded in the audit log
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AuditRecord {
+    /// Unix timestamp in seconds
+    pub ts: u64,
+    /// `snapshot`, `prune`, `rollback`, `clean`, `pin`, `unpin`, `import`,
+    /// `replicate`, `restore-archive` or `sync-restore`
+    pub op: String,
+    /// The volume operated on
+    pub volume: String,
+    /// The requested height, `None` means the latest one for a `rollback`
+    pub target: Option<u64>,
+    /// The height actually rolled back to
+    pub resolved: Option<u64>,
+    /// Snapshots destroyed by this operation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destroyed: Vec<u64>,
+    /// Pid of the caller, the daemon records its peers
+    pub pid: u32,
+    /// Uid of the caller
+    pub uid: u32,
+    /// Whether the operation succeeded
+    pub ok: bool,
+    /// Why the operation failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Run `f` on behalf of a peer of the daemon
+pub(crate) fn with_caller<T>(pid: u32, uid: u32, f: impl FnOnce() -> T) -> T {
+    CALLER.with(|c| c.set(Some((pid, uid))));
+    let res = f();
+    CALLER.with(|c| c.set(None));
+    res
+}
+
+/// Start a record of `op`, the caller is filled in
+pub(crate) fn start(cfg: &BtmCfg, op: &str, target: Option<u64>) -> AuditRecord {
+    let (pid, uid) = CALLER
+        .with(|c| c.get())
+        .unwrap_or_else(|| (std::process::id(), nix::unistd::getuid().as_raw()));
+    AuditRecord {
+        ts: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        op: op.to_owned(),
+        volume: cfg.volume.clone(),
+        target,
+        pid,
+        uid,
+        ..Default::default()
+    }
+}
+
+/// Fill in the outcome and append the record,
+/// a failure of the audit log itself never fails the operation
+pub(crate) fn finish<T>(cfg: &BtmCfg, mut rec: AuditRecord, res: &Result<T>) {
+    rec.ok = res.is_ok();
+    rec.error = res.as_ref().err().map(|e| match e {
+        BtmError::Other(e) => e.get_lowest_msg(),
+        e => e.to_string(),
+    });
+    if let Err(e) = append(cfg, &rec) {
+        Event::new("audit")
+            .volume(&cfg.volume)
+            .fail(LogLevel::Warn, &e);
+    }
+}
+
+fn append(cfg: &BtmCfg, rec: &AuditRecord) -> Result<()> {
+    fs::create_dir_all(STATE_DIR).c(d!())?;
+
+    // the check, the rotation and the append are done under a lock file,
+    // which is never rotated, so that writers of other processes
+    // can not rotate the same file twice
+    let lk = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(state::path(&cfg.volume, "audit.lock"))
+        .c(d!())?;
+    let _lk = Flock::lock(lk, FlockArg::LockExclusive)
+        .map_err(|(_, e)| eg!(e))
+        .c(d!())?;
+
+    let path = audit_path(&cfg.volume, 0);
+    let size = match fs::metadata(&path) {
+        Ok(m) => m.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(eg!(e).into()),
+    };
+    if AUDIT_MAX_SIZE <= size {
+        rotate(&cfg.volume)?;
+    }
+
+    let mut f = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .c(d!())?;
+    let mut line = serde_json::to_vec(rec).c(d!())?;
+    line.push(b'\n');
+    f.write_all(&line).c(d!()).map_err(|e| e.into())
+}
+
+// `x.audit.3` => `x.audit.4`, ..., `x.audit` => `x.audit.1`
+fn rotate(volume: &str) -> Result<()> {
+    for i in (0..AUDIT_KEPT).rev() {
+        match fs::rename(audit_path(volume, i), audit_path(volume, i + 1)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(eg!(e).into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// All records in the audit log, the oldest first
+pub(crate) fn history(cfg: &BtmCfg) -> Result<Vec<AuditRecord>> {
+    let mut res = vec![];
+    for i in (0..=AUDIT_KEPT).rev() {
+        let content = match fs::read_to_string(audit_path(&cfg.volume, i)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(eg!(e).into()),
+        };
+        // a line may be half-written if the writer crashed
+        res.extend(
+            content
+                .lines()
+                .filter_map(|l| serde_json::from_str::<AuditRecord>(l).ok()),
+        );
+    }
+    Ok(res)
+}
+
+#[inline(always)]
+fn audit_path(volume: &str, rotated: usize) -> PathBuf {
+    if 0 == rotated {
+        state::path(volume, "audit")
+    } else {
+        state::path(volume, &format!("audit.{}", rotated))
+    }
+}
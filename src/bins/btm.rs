@@ -8,10 +8,27 @@
 //! btm rollback --volume <VOLUME>
 //! btm rollback --volume <VOLUME> --snapshot-id <IDX>
 //! btm rollback --volume <VOLUME> --snapshot-id <IDX> --strict
+//! btm rollback --volume <VOLUME> --unit <NODE_SERVICE>
+//! btm rollback --volume <VOLUME> --pre-hook <CMD> --post-hook <CMD>
 //! btm clean
 //! btm clean --kept 1
+//! btm pin --snapshot-id <IDX>
+//! btm unpin --snapshot-id <IDX>
+//! btm history --limit 50 --op rollback
+//! btm status --min-free 10737418240
+//! btm export --to /backup/btm
+//! btm import --volume <NEW_VOLUME> --from /backup/btm
+//! btm archive 1024 --output /backup/data-1024.tar.zst
+//! btm restore-archive --volume <NEW_VOLUME> /backup/data-1024.tar.zst
+//! btm sync-snapshot 1024 --dir /var/lib/btm/state-sync --keep 2
+//! btm sync-list --dir /var/lib/btm/state-sync
+//! btm sync-restore --volume <NEW_VOLUME> --dir /mnt/peer/state-sync
+//! btm verify 1024 --validate <CMD>
 //! ```
 //!
+//! These commands are sent to the running daemon of the volume if there is one,
+//! or will operate on the volume directly.
+//!
 //! ## Server Mode
 //!
 //! ```shell
@@ -33,10 +50,19 @@ fn main() {
 
 #[cfg(target_os = "linux")]
 mod cmd {
-    use btm::{run_daemon, BtmCfg, SnapAlgo, SnapMode};
+    use btm::{
+        run_daemon, run_daemon_with_config, AuditRecord, BtmCfg, BtmError, CheckStatus,
+        DaemonClient, DaemonStatus, Hooks, LogFormat, LogLevel, Manifest, SnapAlgo, SnapMode,
+        SyncSnapshot, SyncStore, VerifyReport, DEFAULT_CHUNK_SIZE,
+    };
     use clap::{Parser, Subcommand};
     use ruc::*;
-    use std::env;
+    use std::{
+        env,
+        path::PathBuf,
+        process,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
 
     const ENV_VAR_BTM_VOLUME: &str = "BTM_VOLUME";
 
@@ -47,6 +73,8 @@ mod cmd {
         cmds: Cmds,
     }
 
+    // parsed only once
+    #[allow(clippy::large_enum_variant)]
     #[derive(Debug, Subcommand)]
     enum Cmds {
         #[clap(about = "List all existing snapshots")]
@@ -57,6 +85,11 @@ mod cmd {
                 help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
             )]
             volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
         },
         #[clap(about = "Rollback to the state of an existing snapshot")]
         Rollback {
@@ -66,6 +99,11 @@ mod cmd {
                 help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
             )]
             volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
             #[arg(
                 short,
                 long,
@@ -79,6 +117,23 @@ mod cmd {
                 help = "In this mode, if `snapshot_id` cannot be matched exactly, an error will be returned"
             )]
             strict: bool,
+            #[arg(
+                short,
+                long,
+                conflicts_with_all = ["pre_hook", "post_hook"],
+                help = "A systemd unit to stop before the rollback, and to start after the rollback, refused if a daemon manages the volume"
+            )]
+            unit: Option<String>,
+            #[arg(
+                long,
+                help = "A shell command to execute before the rollback, the rollback will be aborted if it fails, refused if a daemon manages the volume"
+            )]
+            pre_hook: Option<String>,
+            #[arg(
+                long,
+                help = "A shell command to execute after the rollback, refused if a daemon manages the volume"
+            )]
+            post_hook: Option<String>,
         },
         #[clap(about = "Clean all or part of existing snapshots")]
         Clean {
@@ -88,6 +143,11 @@ mod cmd {
                 help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
             )]
             volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
             #[arg(
                 short,
                 long,
@@ -96,14 +156,250 @@ mod cmd {
             )]
             kept: usize,
         },
+        #[clap(about = "Protect a snapshot from being cleaned up")]
+        Pin {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
+            #[arg(short, long, help = "The target snapshot")]
+            snapshot_id: u64,
+        },
+        #[clap(about = "Cancel the protection of a snapshot")]
+        Unpin {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
+            #[arg(short, long, help = "The target snapshot")]
+            snapshot_id: u64,
+        },
+        #[clap(
+            about = "Show the audit log of snapshot, prune, rollback and other mutating operations"
+        )]
+        History {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(
+                short = 'n',
+                long,
+                default_value_t = 20,
+                help = "How many latest records to show, 0 means all"
+            )]
+            limit: usize,
+            #[arg(long, help = "Only show records of this operation, eg. rollback")]
+            op: Option<String>,
+            #[arg(long, help = "Print records as JSON lines")]
+            json: bool,
+        },
+        #[clap(
+            about = "Export new snapshots into a backup store as full or incremental zfs/btrfs streams"
+        )]
+        Export {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(long, help = "Directory of the backup store, created if missing")]
+            to: PathBuf,
+        },
+        #[clap(about = "Restore a snapshot from a backup store into a new volume")]
+        Import {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The new volume to restore into, must not exist, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(long, help = "Directory of the backup store")]
+            from: PathBuf,
+            #[arg(
+                short,
+                long,
+                help = "The snapshot to restore, the latest one in the store if not specified"
+            )]
+            snapshot_id: Option<u64>,
+        },
+        #[clap(
+            about = "Pack the contents of a snapshot into a portable tar.zst archive with SHA-256 checksums"
+        )]
+        Archive {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(help = "The snapshot to archive")]
+            height: u64,
+            #[arg(
+                short,
+                long,
+                help = "Path of the archive, `<volume name>-<height>.tar.zst` in the current directory if not specified"
+            )]
+            output: Option<PathBuf>,
+        },
+        #[clap(about = "Verify an archive and restore it into a new volume of any mode")]
+        RestoreArchive {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The volume to restore into, created if missing, must have neither snapshots nor data, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(
+                short,
+                long,
+                help = "Optional, `zfs` or `btrfs`, case insensitive, will try to automatically identify if not specified"
+            )]
+            mode: Option<String>,
+            #[arg(help = "Path of the archive")]
+            archive: PathBuf,
+        },
+        #[clap(about = "Generate a chunked state-sync snapshot, to be served to peers")]
+        SyncSnapshot {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(help = "The snapshot to generate from")]
+            height: u64,
+            #[arg(long, help = "Directory of the state-sync store, created if missing")]
+            dir: PathBuf,
+            #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE, help = "Size of each chunk in bytes")]
+            chunk_size: u64,
+            #[arg(
+                long,
+                help = "Keep the latest N snapshots in the store, and remove the others"
+            )]
+            keep: Option<usize>,
+        },
+        #[clap(about = "List state-sync snapshots in a store")]
+        SyncList {
+            #[arg(long, help = "Directory of the state-sync store")]
+            dir: PathBuf,
+            #[arg(long, help = "Print snapshots as JSON lines")]
+            json: bool,
+        },
+        #[clap(
+            about = "Verify the chunks of a state-sync snapshot and restore it into a new volume"
+        )]
+        SyncRestore {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The volume to restore into, created if missing, must have neither snapshots nor data, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(
+                short,
+                long,
+                help = "Optional, `zfs` or `btrfs`, case insensitive, will try to automatically identify if not specified"
+            )]
+            mode: Option<String>,
+            #[arg(long, help = "Directory of the state-sync store")]
+            dir: PathBuf,
+            #[arg(help = "The snapshot to restore, the latest one in the store if not specified")]
+            height: Option<u64>,
+            #[arg(
+                long,
+                help = "The trusted hash of the snapshot, got from outside of the store, refuse to restore if it differs"
+            )]
+            hash: String,
+        },
+        #[clap(about = "Check the integrity of a snapshot, exit with 0(passed) or 1(failed)")]
+        Verify {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(help = "The snapshot to verify, the latest one if not specified")]
+            height: Option<u64>,
+            #[arg(
+                long,
+                value_name = "CMD",
+                help = "A shell command validating the contents, executed in the snapshot directory with $BTM_HEIGHT set, refused if the snapshot is writable"
+            )]
+            validate: Option<String>,
+            #[arg(long, help = "Print the report as JSON")]
+            json: bool,
+        },
+        #[clap(
+            about = "Check the health of the daemon and the volume, exit with 0(ok), 1(warning), 2(critical) or 3(unknown)"
+        )]
+        Status {
+            #[arg(
+                short = 'p',
+                long,
+                help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
+            )]
+            volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
+            #[arg(
+                long,
+                help = "Inspect the volume directly, a missing daemon is not a warning in this mode"
+            )]
+            local: bool,
+            #[arg(
+                long,
+                help = "Critical if the free space of the pool or the filesystem is less than this, in bytes"
+            )]
+            min_free: Option<u64>,
+            #[arg(long, help = "Print the status as JSON")]
+            json: bool,
+        },
         #[clap(about = "Run btm as a daemon process")]
         Daemon {
+            #[arg(
+                long,
+                conflicts_with_all = [
+                    "volume", "socket", "itv", "cap", "mode", "algo", "unit", "pre_hook",
+                    "post_hook", "allow_uid", "allow_gid", "queue_size", "metrics", "log_level", "log_format",
+                    "stale_max_age", "stale_max_lag", "stale_alert",
+                    "replica_target", "replica_pipe", "replica_pipe_destroy", "verify_digest"
+                ],
+                help = "A TOML or JSON config file, eg. /etc/btm/btm.toml, will be reloaded on SIGHUP"
+            )]
+            config: Option<PathBuf>,
             #[arg(
                 short = 'p',
                 long,
                 help = "The target volume to operate on, if $BTM_VOLUME is specified, this option can be omitted"
             )]
             volume: Option<String>,
+            #[arg(
+                long,
+                help = "Address of the daemon, an absolute path means a socket file, derived from the volume if not specified"
+            )]
+            socket: Option<String>,
             #[arg(
                 short,
                 long,
@@ -126,6 +422,85 @@ mod cmd {
             mode: Option<String>,
             #[arg(short, long, default_value_t = String::from("Fair"), help = "fair or fade, case insensitive")]
             algo: String,
+            #[arg(
+                short,
+                long,
+                conflicts_with_all = ["pre_hook", "post_hook"],
+                help = "A systemd unit to stop before a rollback, and to start after the rollback"
+            )]
+            unit: Option<String>,
+            #[arg(
+                long,
+                help = "A shell command to execute before a rollback, the rollback will be aborted if it fails"
+            )]
+            pre_hook: Option<String>,
+            #[arg(long, help = "A shell command to execute after a rollback")]
+            post_hook: Option<String>,
+            #[arg(
+                long,
+                help = "A user allowed to send requests to the daemon, can be repeated, root and the owner of the daemon are always allowed"
+            )]
+            allow_uid: Vec<u32>,
+            #[arg(
+                long,
+                help = "A group allowed to send requests to the daemon, can be repeated, only the primary group of the peer is matched"
+            )]
+            allow_gid: Vec<u32>,
+            #[arg(
+                long,
+                default_value_t = 100,
+                help = "How many heights can be waiting in the queue, more requests will be replied with `busy`"
+            )]
+            queue_size: usize,
+            #[arg(
+                long,
+                help = "Serve Prometheus metrics on this address, eg. 127.0.0.1:9185, an absolute path means a unix socket"
+            )]
+            metrics: Option<String>,
+            #[arg(long, default_value_t = String::from("warn"), help = "error, warn, info or debug, case insensitive")]
+            log_level: String,
+            #[arg(long, default_value_t = String::from("text"), help = "text or json, case insensitive")]
+            log_format: String,
+            #[arg(
+                long,
+                help = "Stale if no snapshot has been created within this many seconds"
+            )]
+            stale_max_age: Option<u64>,
+            #[arg(
+                long,
+                help = "Stale if the latest snapshot is this many heights behind the latest requested one, should be larger than `itv`"
+            )]
+            stale_max_lag: Option<u64>,
+            #[arg(
+                long,
+                help = "A shell command to execute when the daemon becomes stale, with $BTM_VOLUME and $BTM_STALE_REASON set"
+            )]
+            stale_alert: Option<String>,
+            #[arg(
+                long,
+                conflicts_with = "replica_pipe",
+                value_name = "TARGET",
+                help = "Replicate new snapshots to this zfs dataset or btrfs subvolume path, on another pool"
+            )]
+            replica_target: Option<String>,
+            #[arg(
+                long,
+                value_name = "CMD",
+                help = "Or pipe the streams of new snapshots to this shell command, eg. `ssh standby zfs recv -F tank/data`"
+            )]
+            replica_pipe: Option<String>,
+            #[arg(
+                long,
+                requires = "replica_pipe",
+                value_name = "CMD",
+                help = "A shell command to destroy snapshots received by --replica-pipe, with $BTM_HEIGHTS set"
+            )]
+            replica_pipe_destroy: Option<String>,
+            #[arg(
+                long,
+                help = "Record the content digest of each new snapshot, to be compared by `btm verify`"
+            )]
+            verify_digest: bool,
         },
     }
 
@@ -137,36 +512,316 @@ mod cmd {
         let cfg = Cfg::parse();
 
         match cfg.cmds {
-            Cmds::List { volume } => volume
-                .c(d!())
-                .or_else(|_| env::var(ENV_VAR_BTM_VOLUME).c(d!()))
-                .and_then(|v| BtmCfg::new(&v, None).c(d!()))?
-                .list_snapshots()
-                .c(d!()),
+            Cmds::List { volume, socket } => {
+                let volume = get_volume(volume).c(d!())?;
+                if let Some(cli) = daemon_of(&volume, socket)? {
+                    println!("Available snapshots are listed below:");
+                    cli.list().c(d!()).map(|list| {
+                        list.into_iter().rev().for_each(|h| {
+                            println!("    {}", h);
+                        })
+                    })
+                } else {
+                    BtmCfg::new(&volume, None).c(d!())?.list_snapshots().c(d!())
+                }
+            }
             Cmds::Rollback {
                 volume,
+                socket,
                 snapshot_id,
                 strict,
-            } => volume
-                .c(d!())
-                .or_else(|_| env::var(ENV_VAR_BTM_VOLUME).c(d!()))
-                .and_then(|v| BtmCfg::new(&v, None).c(d!()))?
-                .rollback(alt!(0 > snapshot_id, None, Some(snapshot_id)), strict)
-                .c(d!()),
-            Cmds::Clean { volume, kept } => volume
-                .c(d!())
-                .or_else(|_| env::var(ENV_VAR_BTM_VOLUME).c(d!()))
-                .and_then(|v| clean_snapshots(&v, kept).c(d!())),
+                unit,
+                pre_hook,
+                post_hook,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let hooks = gen_hooks(unit, pre_hook, post_hook);
+                let local = hooks.pre_rollback.is_some() || hooks.post_rollback.is_some();
+                match daemon_of(&volume, socket)? {
+                    // the index and the queue of the daemon would be left behind,
+                    // and hooks from a request are not run by the daemon
+                    Some(_) if local => Err(eg!(
+                        "a daemon manages {}, roll back without hooks to use its own ones, or stop it first",
+                        volume
+                    )),
+                    Some(cli) => cli
+                        .timeout(Duration::from_secs(600))
+                        .rollback(
+                            alt!(0 > snapshot_id, None, Some(snapshot_id as u64)),
+                            strict,
+                        )
+                        .c(d!()),
+                    None => {
+                        let mut cfg = BtmCfg::new(&volume, None).c(d!())?;
+                        cfg.hooks = hooks;
+                        cfg.rollback(alt!(0 > snapshot_id, None, Some(snapshot_id)), strict)
+                            .c(d!())
+                    }
+                }
+            }
+            Cmds::Clean {
+                volume,
+                socket,
+                kept,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                if let Some(cli) = daemon_of(&volume, socket)? {
+                    cli.timeout(Duration::from_secs(600)).clean(kept).c(d!())
+                } else {
+                    clean_snapshots(&volume, kept).c(d!())
+                }
+            }
+            Cmds::Pin {
+                volume,
+                socket,
+                snapshot_id,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                if let Some(cli) = daemon_of(&volume, socket)? {
+                    cli.pin(snapshot_id).c(d!())
+                } else {
+                    BtmCfg::new(&volume, None).c(d!())?.pin(snapshot_id).c(d!())
+                }
+            }
+            Cmds::History {
+                volume,
+                limit,
+                op,
+                json,
+            } => {
+                // only the volume is needed to locate the audit log
+                let mut cfg = BtmCfg::default();
+                cfg.volume = get_volume(volume).c(d!())?;
+                let mut records = cfg.history().c(d!())?;
+                if let Some(op) = op {
+                    records.retain(|r| r.op == op);
+                }
+                let skip = alt!(0 == limit, 0, records.len().saturating_sub(limit));
+                print_history(&records[skip..], json);
+                Ok(())
+            }
+            Cmds::Export { volume, to } => {
+                let volume = get_volume(volume).c(d!())?;
+                let streams = BtmCfg::new(&volume, None).c(d!())?.export(&to).c(d!())?;
+                if streams.is_empty() {
+                    println!("Nothing to export, the store is up to date");
+                }
+                for s in streams {
+                    println!(
+                        "    {:<12} {:>12} bytes, {}",
+                        s.height,
+                        s.size,
+                        s.parent
+                            .map(|p| format!("incremental from {}", p))
+                            .unwrap_or_else(|| "full".to_owned())
+                    );
+                }
+                Ok(())
+            }
+            Cmds::Import {
+                volume,
+                from,
+                snapshot_id,
+            } => {
+                // the volume does not exist yet, so its mode can not be guessed
+                let mut cfg = BtmCfg::default();
+                cfg.volume = get_volume(volume).c(d!())?;
+                cfg.mode = Manifest::load(&from).c(d!())?.mode;
+                let h = cfg.import(&from, snapshot_id).c(d!())?;
+                println!("{} has been restored to the snapshot {}", cfg.volume, h);
+                Ok(())
+            }
+            Cmds::Archive {
+                volume,
+                height,
+                output,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let output = output.unwrap_or_else(|| {
+                    let name = volume.rsplit('/').next().unwrap_or_default();
+                    PathBuf::from(format!("{}-{}.tar.zst", name, height))
+                });
+                let m = BtmCfg::new(&volume, None)
+                    .c(d!())?
+                    .archive(height, &output)
+                    .c(d!())?;
+                println!(
+                    "{}: {} files, {} bytes of the snapshot {}",
+                    output.display(),
+                    m.files.len(),
+                    m.size(),
+                    m.height
+                );
+                Ok(())
+            }
+            Cmds::RestoreArchive {
+                volume,
+                mode,
+                archive,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let m = BtmCfg::new(&volume, mode.as_deref())
+                    .c(d!())?
+                    .restore_archive(&archive)
+                    .c(d!())?;
+                println!(
+                    "{} has been restored to the snapshot {}, {} files verified",
+                    volume,
+                    m.height,
+                    m.files.len()
+                );
+                Ok(())
+            }
+            Cmds::SyncSnapshot {
+                volume,
+                height,
+                dir,
+                chunk_size,
+                keep,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let store = SyncStore::new(&dir);
+                let s = BtmCfg::new(&volume, None)
+                    .c(d!())?
+                    .sync_snapshot(height, &store, chunk_size)
+                    .c(d!())?;
+                println!(
+                    "state-sync snapshot {}: {} chunks, {} bytes, hash {}",
+                    s.height,
+                    s.chunks.len(),
+                    s.size,
+                    s.hash
+                );
+                if let Some(keep) = keep {
+                    for h in store.prune(keep).c(d!())? {
+                        println!("    {:<12} removed", h);
+                    }
+                }
+                Ok(())
+            }
+            Cmds::SyncList { dir, json } => {
+                print_sync_snapshots(&SyncStore::new(&dir).list().c(d!())?, json);
+                Ok(())
+            }
+            Cmds::SyncRestore {
+                volume,
+                mode,
+                dir,
+                height,
+                hash,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let store = SyncStore::new(&dir);
+                let s = match height {
+                    Some(h) => store.get(h).c(d!())?,
+                    None => store
+                        .list()
+                        .c(d!())?
+                        .into_iter()
+                        .next()
+                        .c(d!("no snapshots in {}", dir.display()))?,
+                };
+                let height = s.height;
+                let mut r = BtmCfg::new(&volume, mode.as_deref())
+                    .c(d!())?
+                    .sync_restore(s, &hash)
+                    .c(d!())?;
+                while let Some(i) = r.next() {
+                    r.apply(i, &store.load_chunk(height, i).c(d!())?).c(d!())?;
+                }
+                let m = r.finish().c(d!())?;
+                println!(
+                    "{} has been restored to the snapshot {}, {} files verified",
+                    volume,
+                    m.height,
+                    m.files.len()
+                );
+                Ok(())
+            }
+            Cmds::Verify {
+                volume,
+                height,
+                validate,
+                json,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let mut cfg = BtmCfg::new(&volume, None).c(d!())?;
+                cfg.verify.validate = validate;
+                let report = cfg.verify(height).c(d!())?;
+                print_verify_report(&report, json);
+                process::exit(alt!(report.ok(), 0, 1));
+            }
+            Cmds::Status {
+                volume,
+                socket,
+                local,
+                min_free,
+                json,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                let daemon = alt!(local, Ok(None), daemon_of(&volume, socket));
+                let st = daemon.as_ref().map_err(|e| eg!(e)).and_then(|d| match d {
+                    Some(cli) => cli.status().c(d!()),
+                    None => BtmCfg::new(&volume, None)
+                        .c(d!())
+                        .and_then(|cfg| cfg.status().c(d!())),
+                });
+                let st = match st {
+                    Ok(st) => st,
+                    Err(e) => {
+                        println!("BTM UNKNOWN - {}", e.get_lowest_msg());
+                        process::exit(Health::Unknown as i32);
+                    }
+                };
+                let daemon_ok = local || daemon.is_ok_and(|d| d.is_some());
+                let (health, problems) = check_health(&st, daemon_ok, min_free);
+                print_status(&st, health, &problems, json);
+                process::exit(health as i32);
+            }
+            Cmds::Unpin {
+                volume,
+                socket,
+                snapshot_id,
+            } => {
+                let volume = get_volume(volume).c(d!())?;
+                if let Some(cli) = daemon_of(&volume, socket)? {
+                    cli.unpin(snapshot_id).c(d!())
+                } else {
+                    BtmCfg::new(&volume, None)
+                        .c(d!())?
+                        .unpin(snapshot_id)
+                        .c(d!())
+                }
+            }
             Cmds::Daemon {
+                config: Some(path), ..
+            } => run_daemon_with_config(&path).c(d!()),
+            Cmds::Daemon {
+                config: None,
                 volume,
+                socket,
                 itv,
                 cap,
                 mode,
                 algo,
+                unit,
+                pre_hook,
+                post_hook,
+                allow_uid,
+                allow_gid,
+                queue_size,
+                metrics,
+                log_level,
+                log_format,
+                stale_max_age,
+                stale_max_lag,
+                stale_alert,
+                replica_target,
+                replica_pipe,
+                replica_pipe_destroy,
+                verify_digest,
             } => {
-                let volume = volume
-                    .c(d!())
-                    .or_else(|_| env::var(ENV_VAR_BTM_VOLUME).c(d!()))?;
+                let volume = get_volume(volume).c(d!())?;
                 let mode = if let Some(m) = mode {
                     let m = SnapMode::from_string(&m).c(d!())?;
                     if matches!(m, SnapMode::External) {
@@ -179,20 +834,327 @@ mod cmd {
 
                 let algo = SnapAlgo::from_string(&algo).c(d!())?;
 
-                let btmcfg = BtmCfg {
-                    itv,
-                    cap,
-                    // useless in this scene
-                    cap_clean_kept: 0,
-                    mode,
-                    algo,
-                    volume,
-                };
+                // config structs are non-exhaustive, fields are set one by one
+                let mut btmcfg = BtmCfg::default();
+                btmcfg.itv = itv;
+                btmcfg.cap = cap;
+                btmcfg.mode = mode;
+                btmcfg.algo = algo;
+                btmcfg.volume = volume;
+                btmcfg.hooks = gen_hooks(unit, pre_hook, post_hook);
+                btmcfg.socket = socket;
+                btmcfg.peers.uids = allow_uid;
+                btmcfg.peers.gids = allow_gid;
+                btmcfg.queue_size = queue_size;
+                btmcfg.metrics = metrics;
+                btmcfg.log.level = LogLevel::from_string(&log_level).c(d!())?;
+                btmcfg.log.format = LogFormat::from_string(&log_format).c(d!())?;
+                btmcfg.stale.max_age = stale_max_age;
+                btmcfg.stale.max_lag = stale_max_lag;
+                btmcfg.stale.alert = stale_alert;
+                btmcfg.replica.target = replica_target;
+                btmcfg.replica.pipe = replica_pipe;
+                btmcfg.replica.pipe_destroy = replica_pipe_destroy;
+                btmcfg.verify.digest = verify_digest;
                 run_daemon(btmcfg).c(d!())
             }
         }
     }
 
+    // Exit codes of Nagios plugins
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    enum Health {
+        Ok = 0,
+        Warning = 1,
+        Critical = 2,
+        Unknown = 3,
+    }
+
+    // The worst health and the reasons of it
+    fn check_health(
+        st: &DaemonStatus,
+        daemon_ok: bool,
+        min_free: Option<u64>,
+    ) -> (Health, Vec<String>) {
+        let mut problems = vec![];
+        let mut health = Health::Ok;
+        let mut found = |h: Health, msg: String| {
+            health = health.max(h);
+            problems.push(msg);
+        };
+
+        if !daemon_ok {
+            found(Health::Warning, "daemon is not running".to_owned());
+        }
+        if 0 == st.count {
+            found(Health::Critical, "no snapshots".to_owned());
+        }
+        if let Some(reason) = &st.stale {
+            found(Health::Critical, format!("stale, {}", reason));
+        }
+        // pinned snapshots are never cleaned up
+        let unpinned = st.count.saturating_sub(st.pinned.len()) as u64;
+        if unpinned > st.cap {
+            found(
+                Health::Warning,
+                format!("{} snapshots exceed the cap {}", unpinned, st.cap),
+            );
+        }
+        if let (Some(free), Some(min)) = (st.free, min_free) {
+            if free < min {
+                found(
+                    Health::Critical,
+                    format!("free space {} is less than {}", bytes(free), bytes(min)),
+                );
+            }
+        }
+        // an error followed by a successful snapshot has been recovered
+        if let (Some(msg), Some(at)) = (&st.last_error, st.last_error_at) {
+            let latest_at = st.latest_age.map(|age| now().saturating_sub(age));
+            if latest_at.map(|t| t <= at).unwrap_or(true) {
+                found(Health::Warning, format!("last error: {}", msg));
+            }
+        }
+
+        (health, problems)
+    }
+
+    fn print_status(st: &DaemonStatus, health: Health, problems: &[String], json: bool) {
+        if json {
+            let mut v = pnk!(serde_json::to_value(st));
+            v["health"] = format!("{:?}", health).to_lowercase().into();
+            v["problems"] = problems.into();
+            println!("{}", v);
+            return;
+        }
+
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_owned());
+        let summary = if problems.is_empty() {
+            format!(
+                "latest snapshot {}, {} snapshots",
+                opt(st.latest.map(|h| h.to_string())),
+                st.count
+            )
+        } else {
+            problems.join(", ")
+        };
+        println!(
+            "BTM {} - {}",
+            format!("{:?}", health).to_uppercase(),
+            summary
+        );
+        println!("volume:     {}", st.volume);
+        println!("uptime:     {}", opt(st.uptime.map(secs)));
+        println!(
+            "config:     mode={} algo={} itv={} cap={}",
+            st.mode, st.algo, st.itv, st.cap
+        );
+        println!(
+            "latest:     {} ({} ago)",
+            opt(st.latest.map(|h| h.to_string())),
+            opt(st.latest_age.map(secs))
+        );
+        println!("requested:  {}", opt(st.requested.map(|h| h.to_string())));
+        println!(
+            "snapshots:  {}/{}, {} pinned",
+            st.count,
+            st.cap,
+            st.pinned.len()
+        );
+        println!("free:       {}", opt(st.free.map(bytes)));
+        println!("replicated: {}", opt(st.replicated.map(|h| h.to_string())));
+        println!(
+            "last error: {}",
+            opt(st.last_error.as_ref().map(|e| format!(
+                "{} at {}",
+                e,
+                utc(st.last_error_at.unwrap_or_default())
+            )))
+        );
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    // eg. `1d2h3m4s`
+    fn secs(s: u64) -> String {
+        let (d, h, m, s) = (s / 86400, s % 86400 / 3600, s % 3600 / 60, s % 60);
+        let mut out = String::new();
+        for (n, unit) in [(d, "d"), (h, "h"), (m, "m")] {
+            if 0 < n || !out.is_empty() {
+                out += &format!("{}{}", n, unit);
+            }
+        }
+        out + &format!("{}s", s)
+    }
+
+    // eg. `1.5GiB`
+    fn bytes(n: u64) -> String {
+        let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+        let mut v = n as f64;
+        let mut i = 0;
+        while 1024.0 <= v && i < units.len() - 1 {
+            v /= 1024.0;
+            i += 1;
+        }
+        alt!(0 == i, format!("{}B", n), format!("{:.1}{}", v, units[i]))
+    }
+
+    fn print_history(records: &[AuditRecord], json: bool) {
+        if json {
+            records
+                .iter()
+                .for_each(|r| println!("{}", pnk!(serde_json::to_string(r))));
+            return;
+        }
+
+        let opt = |h: Option<u64>| h.map(|h| h.to_string()).unwrap_or_else(|| "-".to_owned());
+        println!(
+            "{:<20}  {:<15}  {:>10}  {:>10}  {:>8}  {:>6}  {:<10}  RESULT",
+            "TIME(UTC)", "OP", "TARGET", "RESOLVED", "PID", "UID", "DESTROYED"
+        );
+        for r in records {
+            let destroyed = if r.destroyed.is_empty() {
+                "-".to_owned()
+            } else {
+                r.destroyed
+                    .iter()
+                    .map(|h| h.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            println!(
+                "{:<20}  {:<15}  {:>10}  {:>10}  {:>8}  {:>6}  {:<10}  {}",
+                utc(r.ts),
+                r.op,
+                opt(r.target),
+                opt(r.resolved),
+                r.pid,
+                r.uid,
+                destroyed,
+                r.error.as_deref().unwrap_or("ok")
+            );
+        }
+    }
+
+    fn print_verify_report(report: &VerifyReport, json: bool) {
+        if json {
+            println!("{}", pnk!(serde_json::to_string(report)));
+            return;
+        }
+
+        println!("{}@{}", report.volume, report.height);
+        for c in report.checks.iter() {
+            let status = match c.status {
+                CheckStatus::Ok => "OK",
+                CheckStatus::Failed => "FAILED",
+                CheckStatus::Skipped => "SKIPPED",
+            };
+            println!("  {:<10}{:<9}{}", c.name, status, c.msg);
+        }
+        println!("{}", alt!(report.ok(), "PASSED", "FAILED"));
+    }
+
+    fn print_sync_snapshots(snaps: &[SyncSnapshot], json: bool) {
+        if json {
+            snaps
+                .iter()
+                .for_each(|s| println!("{}", pnk!(serde_json::to_string(s))));
+            return;
+        }
+
+        println!(
+            "{:<12}  {:>6}  {:>8}  {:>10}  {:<20}  HASH",
+            "HEIGHT", "FORMAT", "CHUNKS", "SIZE", "TIME(UTC)"
+        );
+        for s in snaps {
+            println!(
+                "{:<12}  {:>6}  {:>8}  {:>10}  {:<20}  {}",
+                s.height,
+                s.format,
+                s.chunks.len(),
+                bytes(s.size),
+                utc(s.ts),
+                s.hash
+            );
+        }
+    }
+
+    // `YYYY-MM-DD hh:mm:ss` of a unix timestamp,
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    fn utc(ts: u64) -> String {
+        let (days, secs) = ((ts / 86400) as i64, ts % 86400);
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = alt!(mp < 10, mp + 3, mp - 9);
+        let y = yoe + era * 400 + alt!(m <= 2, 1, 0);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            y,
+            m,
+            d,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+
+    fn get_volume(volume: Option<String>) -> Result<String> {
+        volume
+            .c(d!())
+            .or_else(|_| env::var(ENV_VAR_BTM_VOLUME).c(d!()))
+    }
+
+    fn gen_hooks(unit: Option<String>, pre: Option<String>, post: Option<String>) -> Hooks {
+        if let Some(u) = unit {
+            Hooks::systemd(&u)
+        } else {
+            let mut hooks = Hooks::default();
+            hooks.pre_rollback = pre;
+            hooks.post_rollback = post;
+            hooks
+        }
+    }
+
+    // Talk to the daemon only if it is managing the same volume,
+    // `None` if there is no daemon at all;
+    // falling back to a local operation behind a slow daemon
+    // would leave its index and queue describing snapshots that no longer exist
+    fn daemon_of(volume: &str, socket: Option<String>) -> Result<Option<DaemonClient>> {
+        let mut cfg = BtmCfg::new(volume, Some("external")).c(d!())?;
+        cfg.socket = socket;
+        let cli = cfg.daemon_client();
+        match cli
+            .clone()
+            .timeout(Duration::from_secs(3))
+            .retries(0)
+            .status()
+        {
+            Ok(s) => Ok(alt!(s.volume == volume, Some(cli), None)),
+            Err(BtmError::DaemonUnreachable(_)) => Ok(None),
+            Err(BtmError::PermissionDenied(e)) => Err(eg!(
+                "no permission to talk to the daemon on {}, run as root, its owner, \
+                 or a uid/gid allowed by it: {}",
+                cfg.daemon_socket(),
+                e
+            )),
+            Err(e) => Err(eg!(
+                "the daemon on {} does not respond, try again later: {}",
+                cfg.daemon_socket(),
+                e
+            )),
+        }
+    }
+
     fn clean_snapshots(volume: &str, kept: usize) -> Result<()> {
         let mut cfg = BtmCfg::new(volume, None).c(d!())?;
         cfg.cap_clean_kept = kept;
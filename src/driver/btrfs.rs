@@ -1,87 +1,98 @@
-use crate::{BtmCfg, SnapAlgo, STEP_CNT};
-use ruc::{cmd::exec_output, *};
-use std::path::PathBuf;
+use super::exec_output;
+use crate::{BtmCfg, Result};
+use nix::sys::statvfs::statvfs;
+use ruc::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
 
 #[inline(always)]
-pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
-    if sorted_snapshots(cfg).c(d!())?.contains(&idx) {
-        return Err(eg!("Snapshot {} already exists!", idx));
-    }
-
-    alt!(0 != (u64::MAX - idx) % cfg.itv, return Ok(()));
-    clean_outdated(cfg).c(d!())?;
-    let cmd = format!(
+pub(crate) fn snapshot_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!(
         "
             btrfs subvolume delete {0}@{1} 2>/dev/null;
-            btrfs subvolume snapshot {0} {0}@{1}
+            btrfs subvolume snapshot -r {0} {0}@{1}
             ",
         &cfg.volume, idx
-    );
-    exec_output(&cmd).c(d!()).map(|_| ())
+    )
 }
 
-pub(crate) fn sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
-    let cmd = format!(
-        r"btrfs subvolume list -so {} | grep -o '@[0-9]\+$' | sed 's/@//'",
-        PathBuf::from(&cfg.volume)
-            .parent()
-            .c(d!())?
-            .to_str()
-            .c(d!())?
-    );
-    let output = exec_output(&cmd).c(d!())?;
+#[inline(always)]
+pub(crate) fn list_cmd(cfg: &BtmCfg) -> Result<String> {
+    let parent = Path::new(&cfg.volume).parent().c(d!())?;
+    Ok(format!(
+        "btrfs subvolume list -so {}",
+        parent.to_str().c(d!())?
+    ))
+}
 
-    let mut res = output
-        .lines()
-        .map(|l| l.parse::<u64>().c(d!()))
-        .collect::<Result<Vec<u64>>>()?;
-    res.sort_unstable_by(|a, b| b.cmp(a));
-    //res.dedup();
+pub(crate) fn parse_list(cfg: &BtmCfg, output: &str) -> Result<Vec<u64>> {
+    let name = Path::new(&cfg.volume).file_name().c(d!())?;
+    let prefix = format!("{}@", name.to_string_lossy());
 
+    // the last field of each line is the path of a snapshot
+    let res = output
+        .lines()
+        .filter_map(|l| l.split_whitespace().last())
+        .filter_map(|p| Path::new(p).file_name()?.to_str()?.strip_prefix(&prefix))
+        .filter_map(|h| h.parse::<u64>().ok())
+        .collect();
     Ok(res)
 }
 
-pub(crate) fn rollback(cfg: &BtmCfg, idx: Option<i128>, strict: bool) -> Result<()> {
-    let mut snaps = sorted_snapshots(cfg).c(d!())?;
-    // convert to AESC order for `binary_search`
-    snaps.reverse();
-    alt!(snaps.is_empty(), return Err(eg!("no snapshots")));
-
-    let idx = idx
-        .map(|i| i as u64)
-        .unwrap_or_else(|| snaps[snaps.len() - 1]);
-
-    let cmd = match snaps.binary_search(&idx) {
-        Ok(_) => {
-            format!(
-                "
-                    btrfs subvolume delete {0} 2>/dev/null;
-                    btrfs subvolume snapshot {0}@{1} {0}
-                    ",
-                &cfg.volume, idx
-            )
-        }
-        Err(i) => {
-            if strict {
-                return Err(eg!("specified height does not exist"));
-            } else {
-                let effective_idx = if 1 + i > snaps.len() {
-                    snaps[snaps.len() - 1]
-                } else {
-                    *(0..i).rev().find_map(|i| snaps.get(i)).c(d!())?
-                };
-                format!(
-                    "
-                        btrfs subvolume delete {0} 2>/dev/null;
-                        btrfs subvolume snapshot {0}@{1} {0}
-                        ",
-                    &cfg.volume, effective_idx
-                )
-            }
-        }
-    };
+#[inline(always)]
+pub(crate) fn rollback_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!(
+        "
+            btrfs subvolume delete {0} 2>/dev/null;
+            btrfs subvolume snapshot {0}@{1} {0}
+            ",
+        &cfg.volume, idx
+    )
+}
+
+// Snapshots created by older versions are writable,
+// but only read-only ones can be sent
+#[inline(always)]
+pub(crate) fn send_cmd(cfg: &BtmCfg, parent: Option<u64>, idx: u64) -> String {
+    let parent = parent
+        .map(|p| format!("-p {}@{} ", &cfg.volume, p))
+        .unwrap_or_default();
+    format!(
+        "btrfs property set -ts {0}@{1} ro true && btrfs send {2}{0}@{1}",
+        &cfg.volume, idx, parent
+    )
+}
+
+// A received snapshot keeps the name of the sent one,
+// so it is renamed after the new volume
+pub(crate) fn recv_cmd(cfg: &BtmCfg, origin: &str, idx: u64) -> Result<String> {
+    let dir = Path::new(&cfg.volume).parent().c(d!())?.to_str().c(d!())?;
+    let name = Path::new(origin).file_name().c(d!())?.to_string_lossy();
+    let received = format!("{}/{}@{}", dir, name, idx);
+    let target = format!("{}@{}", &cfg.volume, idx);
+    let mut cmd = format!("btrfs receive {}", dir);
+    if received != target {
+        cmd += &format!(" && mv {} {}", received, target);
+    }
+    Ok(cmd)
+}
 
-    exec_output(&cmd).c(d!()).map(|_| ())
+// Delete all snapshots within one command
+pub(crate) fn destroy_cmds(cfg: &BtmCfg, heights: &[u64]) -> Vec<String> {
+    if heights.is_empty() {
+        return vec![];
+    }
+
+    let list = heights
+        .iter()
+        .map(|h| format!("{}@{}", &cfg.volume, h))
+        .collect::<Vec<_>>();
+
+    // vec![format!("btrfs subvolume delete -c {}", list.join(" "))]
+    vec![format!("btrfs subvolume delete {}", list.join(" "))]
 }
 
 #[inline(always)]
@@ -90,94 +101,64 @@ pub(crate) fn check(volume: &str) -> Result<()> {
         "btrfs subvolume list {0} || btrfs subvolume create {0}",
         volume
     );
-    exec_output(&cmd).c(d!()).map(|_| ())
+    exec_output(&cmd).map(|_| ())
 }
 
+// A btrfs volume is a subvolume path in itself
 #[inline(always)]
-fn clean_outdated(cfg: &BtmCfg) -> Result<()> {
-    match cfg.algo {
-        SnapAlgo::Fair => clean_outdated_fair(cfg).c(d!()),
-        SnapAlgo::Fade => clean_outdated_fade(cfg).c(d!()),
-    }
+pub(crate) fn mountpoint(cfg: &BtmCfg) -> Result<Option<PathBuf>> {
+    Ok(Some(PathBuf::from(&cfg.volume)))
 }
 
-fn clean_outdated_fair(cfg: &BtmCfg) -> Result<()> {
-    let snaps = sorted_snapshots(cfg).c(d!())?;
-    let cap = cfg.get_cap() as usize;
-
-    if 1 + cap > snaps.len() {
-        return Ok(());
-    }
-
-    let list = snaps[cap..].iter().fold(String::new(), |acc, i| {
-        acc + &format!("{}@{} ", &cfg.volume, i)
-    });
-
-    // let cmd = format!("btrfs subvolume delete -c {}", list);
-    let cmd = format!("btrfs subvolume delete {}", list);
-    info_omit!(exec_output(cmd.trim_end()));
-
-    Ok(())
+#[inline(always)]
+pub(crate) fn snapshot_path(cfg: &BtmCfg, idx: u64) -> PathBuf {
+    PathBuf::from(format!("{}@{}", &cfg.volume, idx))
 }
 
-// Logical steps:
-//
-// 1. clean up outdated snapshot in each chunks
-// > # Example
-// > - itv = 10
-// > - cap = 100
-// > - step_cnt = 5
-// > - chunk_size = 100 / 5 = 20
-// >
-// > blocks cover = chunk_size * (itv^1 + itv^2 ... itv^step_cnt)
-// >              = 55_5500
-// >
-// > this means we can use 100 snapshots to cover 55_5500 blocks
-//
-// 2. clean up snapshot whose indexs exceed `cap`
-// > this means we can use 100 snapshots to cover 55_5500 blocks
-fn clean_outdated_fade(cfg: &BtmCfg) -> Result<()> {
-    let snaps = sorted_snapshots(cfg).c(d!())?;
-    let cap = cfg.get_cap() as usize;
-
-    cfg.check().c(d!())?;
-    let chunk_size = cap / STEP_CNT;
-    let chunk_denominators = (0..STEP_CNT as u32).map(|n| cfg.itv.pow(1 + n));
-
-    if 1 + chunk_size > snaps.len() {
-        return Ok(());
-    }
-
-    let mut to_del = vec![];
+#[inline(always)]
+pub(crate) fn read_only_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!("btrfs property get -ts {}@{} ro", &cfg.volume, idx)
+}
 
-    // 1.
-    let mut pair = (&snaps[..0], &snaps[..]);
-    for denominator in chunk_denominators {
-        pair = if chunk_size < pair.1.len() {
-            pair.1.split_at(chunk_size)
-        } else {
-            (pair.1, &[])
-        };
+#[inline(always)]
+pub(crate) fn device_stats_cmd(cfg: &BtmCfg) -> String {
+    format!("btrfs device stats {}", &cfg.volume)
+}
 
-        pair.0.iter().for_each(|n| {
-            if 0 != (u64::MAX - n) % denominator {
-                to_del.push(format!("{}@{}", &cfg.volume, n));
+// Healthy if all error counters of the devices are zero,
+// checksum failures are counted as `corruption_errs`
+pub(crate) fn parse_device_stats(output: &str) -> (bool, String) {
+    let errors = output
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            match (fields.next(), fields.next().map(|n| n.parse::<u64>())) {
+                (Some(name), Some(Ok(n))) if n > 0 => Some(format!("{} {}", name, n)),
+                _ => None,
             }
-        });
-    }
-
-    // 2.
-    if cap < snaps.len() {
-        snaps[cap..].iter().for_each(|n| {
-            to_del.push(format!("{}@{}", &cfg.volume, n));
-        });
+        })
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        (true, "no device errors".to_owned())
+    } else {
+        (false, errors.join(", "))
     }
+}
 
-    if !to_del.is_empty() {
-        // let cmd = format!("btrfs subvolume delete -c {}", to_del.join(" "));
-        let cmd = format!("btrfs subvolume delete {}", to_del.join(" "));
-        info_omit!(exec_output(&cmd));
-    }
+pub(crate) fn free_space(cfg: &BtmCfg) -> Result<u64> {
+    let st = statvfs(cfg.volume.as_str()).c(d!())?;
+    Ok(st.blocks_available() as u64 * st.fragment_size() as u64)
+}
 
-    Ok(())
+// Snapshots are read-only, so the modification time is the creation time
+pub(crate) fn created_at(cfg: &BtmCfg, idx: u64) -> Result<u64> {
+    let path = format!("{}@{}", &cfg.volume, idx);
+    let meta = fs::metadata(path).c(d!())?;
+    meta.created()
+        .or_else(|_| meta.modified())
+        .c(d!())?
+        .duration_since(UNIX_EPOCH)
+        .c(d!())
+        .map(|d| d.as_secs())
+        .map_err(From::from)
 }
@@ -2,10 +2,9 @@
 //! Only useful in client-end
 //!
 
-use crate::{api::client, BtmCfg};
-use ruc::*;
+use crate::{BtmCfg, Result};
 
 #[inline(always)]
-pub(crate) fn gen_snapshot(_cfg: &BtmCfg, idx: u64) -> Result<()> {
-    client::request_snapshot(idx).c(d!())
+pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    cfg.daemon_client().snapshot(idx)
 }
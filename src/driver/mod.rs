@@ -1,3 +1,567 @@
+//!
+//! # Snapshot drivers
+//!
+//! Each driver only knows how to build its commands,
+//! all decisions are made here by pure functions,
+//! so that the blocking and the async implementations share them.
+//!
+
 pub mod btrfs;
 pub mod external;
 pub mod zfs;
+
+use crate::{
+    audit,
+    logging::{Event, LogLevel},
+    metrics, pin, BtmCfg, BtmError, Result, SnapAlgo, SnapMode, STEP_CNT,
+};
+use ruc::*;
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    time::Instant,
+};
+
+/// Execute a shell command, and return its stdout after it exits.
+///
+/// Unlike `ruc::cmd::exec_output`,
+/// a missing command can be distinguished from other failures.
+pub(crate) fn exec_output(cmd: &str) -> Result<String> {
+    let res = Command::new("bash").arg("-c").arg(cmd).output().c(d!())?;
+    check_output(cmd, res)
+}
+
+pub(crate) fn check_output(cmd: &str, res: Output) -> Result<String> {
+    let stderr = String::from_utf8_lossy(&res.stderr).into_owned();
+    match res.status.code() {
+        Some(0) => Ok(String::from_utf8_lossy(&res.stdout).into_owned()),
+        Some(127) => Err(BtmError::ToolMissing(stderr)),
+        _ => Err(BtmError::Command {
+            cmd: cmd.to_owned(),
+            stderr,
+        }),
+    }
+}
+
+pub(crate) fn sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
+    let output = exec_output(&list_cmd(cfg)?)?;
+    parse_list(cfg, &output)
+}
+
+pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    let snaps = sorted_snapshots(cfg)?;
+    if snaps.contains(&idx) {
+        return Err(BtmError::SnapshotExists(idx));
+    }
+
+    alt!(!itv_matched(cfg, idx), return Ok(()));
+
+    let mut rec = audit::start(cfg, "snapshot", Some(idx));
+    rec.destroyed = destroy(cfg, &outdated(cfg, &snaps)?);
+    let cmd = snapshot_cmd(cfg, idx)?;
+    let res = metrics::timed(cfg, "snapshot", || exec_output(&cmd)).map(|_| ());
+    audit::finish(cfg, rec, &res);
+    res
+}
+
+/// Return the height rolled back to, and the snapshots destroyed by the rollback
+pub(crate) fn rollback(cfg: &BtmCfg, idx: Option<i128>, strict: bool) -> Result<(u64, Vec<u64>)> {
+    let snaps = sorted_snapshots(cfg)?;
+    let target = resolve_rollback(&snaps, idx, strict)?;
+    check_pinned_later(cfg, &snaps, target, &pin::load(cfg)?)?;
+    let cmd = rollback_cmd(cfg, target)?;
+    metrics::timed(cfg, "rollback", || exec_output(&cmd))
+        .map(|_| (target, rollback_destroyed(cfg, &snaps, target)))
+}
+
+// `zfs rollback -r` destroys all snapshots later than the target
+pub(crate) fn rollback_destroyed(cfg: &BtmCfg, snaps: &[u64], target: u64) -> Vec<u64> {
+    match cfg.mode {
+        SnapMode::Zfs => snaps.iter().filter(|&&h| h > target).copied().collect(),
+        SnapMode::Btrfs | SnapMode::External => vec![],
+    }
+}
+
+// Failures are logged and omitted,
+// they will be retried in the next round of cleaning.
+//
+// Return the heights that have been destroyed,
+// pinned ones and the ones of failed commands are not included.
+pub(crate) fn destroy(cfg: &BtmCfg, heights: &[u64]) -> Vec<u64> {
+    // destroy nothing if the pin list is unknown
+    let heights = match pin::unpinned(cfg, heights) {
+        Ok(hs) => hs,
+        Err(e) => {
+            Event::new("prune")
+                .volume(&cfg.volume)
+                .fail(LogLevel::Error, &e);
+            return vec![];
+        }
+    };
+    alt!(heights.is_empty(), return heights);
+
+    let start = Instant::now();
+    let cmds = destroy_cmds(cfg, &heights);
+    let failed = cmds
+        .iter()
+        .enumerate()
+        .filter(|(i, cmd)| {
+            exec_output(cmd)
+                .map_err(|e| destroy_failed(cfg, &heights, cmds.len(), *i, &e))
+                .is_err()
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    metrics::observe(cfg, "prune", start, failed.is_empty());
+    destroy_done(cfg, &heights, failed.len(), start);
+    destroyed(&heights, cmds.len(), &failed)
+}
+
+// Zfs destroys snapshots one by one, and btrfs destroys them all at once,
+// `failed` are the indexes of the failed commands
+pub(crate) fn destroyed(heights: &[u64], n: usize, failed: &[usize]) -> Vec<u64> {
+    if n == heights.len() {
+        heights
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !failed.contains(i))
+            .map(|(_, h)| *h)
+            .collect()
+    } else if failed.is_empty() {
+        heights.to_vec()
+    } else {
+        vec![]
+    }
+}
+
+pub(crate) fn destroy_failed(cfg: &BtmCfg, heights: &[u64], n: usize, i: usize, e: &BtmError) {
+    let height = alt!(n == heights.len(), Some(heights[i]), None);
+    Event::new("prune")
+        .volume(&cfg.volume)
+        .height(height)
+        .fail(LogLevel::Warn, e);
+}
+
+pub(crate) fn destroy_done(cfg: &BtmCfg, heights: &[u64], failed: usize, start: Instant) {
+    let ev = Event::new("prune").volume(&cfg.volume).since(start);
+    if 0 == failed {
+        ev.info(format!("{} snapshots destroyed", heights.len()));
+    } else {
+        ev.warn(format!(
+            "{} snapshots to destroy, {} commands failed",
+            heights.len(),
+            failed
+        ));
+    }
+}
+
+/// Space used by all snapshots in bytes, `None` if unknown in this mode
+pub(crate) fn space_used(cfg: &BtmCfg) -> Result<Option<u64>> {
+    match cfg.mode {
+        SnapMode::Zfs => parse_u64(&exec_output(&zfs::space_used_cmd(cfg))?).map(Some),
+        SnapMode::Btrfs | SnapMode::External => Ok(None),
+    }
+}
+
+/// Free space of the pool or the filesystem holding the volume, in bytes
+pub(crate) fn free_space(cfg: &BtmCfg) -> Result<u64> {
+    match cfg.mode {
+        SnapMode::Zfs => parse_u64(&exec_output(&zfs::free_space_cmd(cfg))?),
+        SnapMode::Btrfs => btrfs::free_space(cfg),
+        SnapMode::External => Err(unsupported(cfg, "status")),
+    }
+}
+
+/// When the snapshot was created, in unix seconds
+pub(crate) fn created_at(cfg: &BtmCfg, idx: u64) -> Result<u64> {
+    match cfg.mode {
+        SnapMode::Zfs => parse_u64(&exec_output(&zfs::creation_cmd(cfg, idx))?),
+        SnapMode::Btrfs => btrfs::created_at(cfg, idx),
+        SnapMode::External => Err(unsupported(cfg, "status")),
+    }
+}
+
+// Outputs of `zfs get -Hp` are numbers in the raw form
+fn parse_u64(output: &str) -> Result<u64> {
+    output
+        .trim()
+        .parse::<u64>()
+        .c(d!("invalid output: {}", output.trim()))
+        .map_err(From::from)
+}
+
+// `zfs rollback -r` destroys all snapshots later than the target
+pub(crate) fn check_pinned_later(
+    cfg: &BtmCfg,
+    snaps: &[u64],
+    target: u64,
+    pins: &BTreeSet<u64>,
+) -> Result<()> {
+    if let SnapMode::Zfs = cfg.mode {
+        if let Some(h) = snaps.iter().find(|&h| *h > target && pins.contains(h)) {
+            return Err(BtmError::Pinned(*h));
+        }
+    }
+    Ok(())
+}
+
+/// Snapshots are only created at `itv` intervals
+#[inline(always)]
+pub(crate) fn itv_matched(cfg: &BtmCfg, idx: u64) -> bool {
+    0 == (u64::MAX - idx) % cfg.itv
+}
+
+/// Parse the output of `list_cmd`, in 'DESC' order
+pub(crate) fn parse_list(cfg: &BtmCfg, output: &str) -> Result<Vec<u64>> {
+    let mut res = match cfg.mode {
+        SnapMode::Zfs => zfs::parse_list(cfg, output)?,
+        SnapMode::Btrfs => btrfs::parse_list(cfg, output)?,
+        SnapMode::External => return Err(unsupported(cfg, "list")),
+    };
+    res.sort_unstable_by(|a, b| b.cmp(a));
+    //res.dedup();
+    Ok(res)
+}
+
+pub(crate) fn list_cmd(cfg: &BtmCfg) -> Result<String> {
+    match cfg.mode {
+        SnapMode::Zfs => zfs::list_cmd(cfg),
+        SnapMode::Btrfs => btrfs::list_cmd(cfg),
+        SnapMode::External => Err(unsupported(cfg, "list")),
+    }
+}
+
+pub(crate) fn snapshot_cmd(cfg: &BtmCfg, idx: u64) -> Result<String> {
+    match cfg.mode {
+        SnapMode::Zfs => Ok(zfs::snapshot_cmd(cfg, idx)),
+        SnapMode::Btrfs => Ok(btrfs::snapshot_cmd(cfg, idx)),
+        SnapMode::External => Err(unsupported(cfg, "snapshot")),
+    }
+}
+
+pub(crate) fn rollback_cmd(cfg: &BtmCfg, idx: u64) -> Result<String> {
+    match cfg.mode {
+        SnapMode::Zfs => Ok(zfs::rollback_cmd(cfg, idx)),
+        SnapMode::Btrfs => Ok(btrfs::rollback_cmd(cfg, idx)),
+        SnapMode::External => Err(unsupported(cfg, "rollback")),
+    }
+}
+
+pub(crate) fn destroy_cmds(cfg: &BtmCfg, heights: &[u64]) -> Vec<String> {
+    match cfg.mode {
+        SnapMode::Zfs => zfs::destroy_cmds(cfg, heights),
+        SnapMode::Btrfs => btrfs::destroy_cmds(cfg, heights),
+        SnapMode::External => vec![],
+    }
+}
+
+/// A command writing the stream of a snapshot to its stdout,
+/// incremental from `parent` if any
+pub(crate) fn send_cmd(cfg: &BtmCfg, parent: Option<u64>, idx: u64) -> Result<String> {
+    match cfg.mode {
+        SnapMode::Zfs => Ok(zfs::send_cmd(cfg, parent, idx)),
+        SnapMode::Btrfs => Ok(btrfs::send_cmd(cfg, parent, idx)),
+        SnapMode::External => Err(unsupported(cfg, "send")),
+    }
+}
+
+/// A command receiving a stream from its stdin as the snapshot `idx` of the volume,
+/// `origin` is the volume it was sent from,
+/// `force` discards changes made on the volume after its latest snapshot, zfs only
+pub(crate) fn recv_cmd(cfg: &BtmCfg, origin: &str, idx: u64, force: bool) -> Result<String> {
+    match cfg.mode {
+        SnapMode::Zfs => Ok(zfs::recv_cmd(cfg, force)),
+        SnapMode::Btrfs => btrfs::recv_cmd(cfg, origin, idx),
+        SnapMode::External => Err(unsupported(cfg, "receive")),
+    }
+}
+
+/// Write the stream of a snapshot into `file`, see [send_cmd]
+pub(crate) fn send(cfg: &BtmCfg, parent: Option<u64>, idx: u64, file: &Path) -> Result<()> {
+    let cmd = send_cmd(cfg, parent, idx)?;
+    let f = File::create(file).c(d!())?;
+    exec_with(&cmd, Stdio::null(), Stdio::from(f))
+}
+
+/// Receive a stream written by [send], see [recv_cmd]
+pub(crate) fn recv(cfg: &BtmCfg, origin: &str, idx: u64, full: bool, file: &Path) -> Result<()> {
+    // the volume may have been changed after the last stream, eg. the access time
+    let cmd = recv_cmd(cfg, origin, idx, !full)?;
+    let f = File::open(file).c(d!())?;
+    exec_with(&cmd, Stdio::from(f), Stdio::null())
+}
+
+// Files are passed as stdio instead of redirections,
+// so that their paths are never parsed by the shell
+fn exec_with(cmd: &str, stdin: Stdio, stdout: Stdio) -> Result<()> {
+    let res = Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(stdin)
+        .stdout(stdout)
+        .output()
+        .c(d!())?;
+    check_output(cmd, res).map(|_| ())
+}
+
+/// Make the received snapshot `idx` the live data of the volume
+pub(crate) fn recv_done(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    match cfg.mode {
+        // the volume has been at the state of the last received snapshot
+        SnapMode::Zfs => Ok(()),
+        SnapMode::Btrfs => exec_output(&btrfs::rollback_cmd(cfg, idx)).map(|_| ()),
+        SnapMode::External => Err(unsupported(cfg, "import")),
+    }
+}
+
+/// Whether the volume itself exists
+pub(crate) fn exists(cfg: &BtmCfg) -> Result<bool> {
+    match cfg.mode {
+        SnapMode::Zfs => Ok(exec_output(&zfs::exists_cmd(cfg)).is_ok()),
+        SnapMode::Btrfs => Ok(Path::new(&cfg.volume).exists()),
+        SnapMode::External => Err(unsupported(cfg, "import")),
+    }
+}
+
+/// Create the volume if it does not exist
+pub(crate) fn create(cfg: &BtmCfg) -> Result<()> {
+    match cfg.mode {
+        SnapMode::Zfs => zfs::check(&cfg.volume),
+        SnapMode::Btrfs => btrfs::check(&cfg.volume),
+        SnapMode::External => Err(unsupported(cfg, "create")),
+    }
+}
+
+/// The mount point of the volume, `None` if it is not mounted
+pub(crate) fn mountpoint(cfg: &BtmCfg) -> Result<Option<PathBuf>> {
+    match cfg.mode {
+        SnapMode::Zfs => zfs::mountpoint(cfg),
+        SnapMode::Btrfs => btrfs::mountpoint(cfg),
+        SnapMode::External => Ok(None),
+    }
+}
+
+/// The directory holding the read-only contents of the snapshot `idx`
+pub(crate) fn snapshot_path(cfg: &BtmCfg, idx: u64) -> Result<PathBuf> {
+    match cfg.mode {
+        SnapMode::Zfs => zfs::snapshot_path(cfg, idx),
+        SnapMode::Btrfs => Ok(btrfs::snapshot_path(cfg, idx)),
+        SnapMode::External => Err(unsupported(cfg, "read")),
+    }
+}
+
+/// Whether the snapshot `idx` can not be modified,
+/// btrfs snapshots created by older versions are writable
+pub(crate) fn read_only(cfg: &BtmCfg, idx: u64) -> Result<bool> {
+    match cfg.mode {
+        SnapMode::Zfs => Ok(true),
+        SnapMode::Btrfs => {
+            exec_output(&btrfs::read_only_cmd(cfg, idx)).map(|o| "ro=true" == o.trim())
+        }
+        SnapMode::External => Err(unsupported(cfg, "verify")),
+    }
+}
+
+/// Whether the pool holding the snapshot `idx` has no known data errors,
+/// and a summary of its status
+pub(crate) fn pool_status(cfg: &BtmCfg, idx: u64) -> Result<(bool, String)> {
+    match cfg.mode {
+        SnapMode::Zfs => {
+            exec_output(&zfs::pool_status_cmd(cfg)).map(|o| zfs::parse_pool_status(cfg, idx, &o))
+        }
+        SnapMode::Btrfs => {
+            exec_output(&btrfs::device_stats_cmd(cfg)).map(|o| btrfs::parse_device_stats(&o))
+        }
+        SnapMode::External => Err(unsupported(cfg, "verify")),
+    }
+}
+
+#[inline(always)]
+fn unsupported(cfg: &BtmCfg, op: &'static str) -> BtmError {
+    BtmError::Unsupported { mode: cfg.mode, op }
+}
+
+/// Find out the snapshot to rollback to,
+/// `snaps` should be in 'DESC' order
+pub(crate) fn resolve_rollback(snaps: &[u64], idx: Option<i128>, strict: bool) -> Result<u64> {
+    // convert to AESC order for `binary_search`
+    let snaps = snaps.iter().rev().copied().collect::<Vec<_>>();
+    alt!(snaps.is_empty(), return Err(BtmError::NoSnapshots));
+
+    let idx = idx
+        .map(|i| i as u64)
+        .unwrap_or_else(|| snaps[snaps.len() - 1]);
+
+    match snaps.binary_search(&idx) {
+        Ok(_) => Ok(idx),
+        Err(i) => {
+            if strict {
+                Err(BtmError::HeightNotFound(idx))
+            } else if 1 + i > snaps.len() {
+                Ok(snaps[snaps.len() - 1])
+            } else {
+                (0..i)
+                    .rev()
+                    .find_map(|i| snaps.get(i))
+                    .copied()
+                    .ok_or(BtmError::HeightNotFound(idx))
+            }
+        }
+    }
+}
+
+/// Snapshots that should be cleaned up,
+/// `snaps` should be in 'DESC' order
+pub(crate) fn outdated(cfg: &BtmCfg, snaps: &[u64]) -> Result<Vec<u64>> {
+    match cfg.algo {
+        SnapAlgo::Fair => Ok(outdated_fair(cfg, snaps)),
+        SnapAlgo::Fade => outdated_fade(cfg, snaps),
+    }
+}
+
+fn outdated_fair(cfg: &BtmCfg, snaps: &[u64]) -> Vec<u64> {
+    let cap = cfg.get_cap() as usize;
+
+    if 1 + cap > snaps.len() {
+        return vec![];
+    }
+
+    snaps[cap..].to_vec()
+}
+
+// Logical steps:
+//
+// 1. clean up outdated snapshot in each chunks
+// > # Example
+// > - itv = 10
+// > - cap = 100
+// > - step_cnt = 5
+// > - chunk_size = 100 / 5 = 20
+// >
+// > blocks cover = chunk_size * (itv^1 + itv^2 ... itv^step_cnt)
+// >              = 55_5500
+// >
+// > this means we can use 100 snapshots to cover 55_5500 blocks
+//
+// 2. clean up snapshot whose indexs exceed `cap`
+fn outdated_fade(cfg: &BtmCfg, snaps: &[u64]) -> Result<Vec<u64>> {
+    let cap = cfg.get_cap() as usize;
+
+    cfg.check()?;
+    let chunk_size = cap / STEP_CNT;
+    let chunk_denominators = (0..STEP_CNT as u32).map(|n| cfg.itv.pow(1 + n));
+
+    if 1 + chunk_size > snaps.len() {
+        return Ok(vec![]);
+    }
+
+    let mut to_del = vec![];
+
+    // 1.
+    let mut pair = (&snaps[..0], snaps);
+    for denominator in chunk_denominators {
+        pair = if chunk_size < pair.1.len() {
+            pair.1.split_at(chunk_size)
+        } else {
+            (pair.1, &[])
+        };
+
+        pair.0.iter().for_each(|n| {
+            if 0 != (u64::MAX - n) % denominator {
+                to_del.push(*n);
+            }
+        });
+    }
+
+    // 2.
+    if cap < snaps.len() {
+        to_del.extend_from_slice(&snaps[cap..]);
+    }
+
+    Ok(to_del)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNAPS: &[u64] = &[45, 35, 25, 15, 5];
+
+    fn cfg(algo: SnapAlgo, itv: u64, cap: u64) -> BtmCfg {
+        let mut cfg = BtmCfg::new("tank/test", Some("zfs")).unwrap();
+        cfg.algo = algo;
+        cfg.itv = itv;
+        cfg.cap = cap;
+        cfg
+    }
+
+    #[test]
+    fn exclude_failed_heights() {
+        // one command for each height
+        assert_eq!(vec![35, 15], destroyed(&[35, 25, 15], 3, &[1]));
+        assert_eq!(vec![35, 25, 15], destroyed(&[35, 25, 15], 3, &[]));
+        assert!(destroyed(&[35, 25, 15], 3, &[0, 1, 2]).is_empty());
+        // one command for all heights
+        assert_eq!(vec![35, 25, 15], destroyed(&[35, 25, 15], 1, &[]));
+        assert!(destroyed(&[35, 25, 15], 1, &[0]).is_empty());
+        assert!(destroyed(&[], 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn resolve_the_rollback_target() {
+        assert_eq!(45, resolve_rollback(SNAPS, None, false).unwrap());
+        assert_eq!(25, resolve_rollback(SNAPS, Some(25), true).unwrap());
+        assert_eq!(25, resolve_rollback(SNAPS, Some(30), false).unwrap());
+        assert_eq!(45, resolve_rollback(SNAPS, Some(100), false).unwrap());
+        assert!(matches!(
+            resolve_rollback(SNAPS, Some(30), true),
+            Err(BtmError::HeightNotFound(30))
+        ));
+        assert!(matches!(
+            resolve_rollback(SNAPS, Some(1), false),
+            Err(BtmError::HeightNotFound(1))
+        ));
+        assert!(matches!(
+            resolve_rollback(&[], None, false),
+            Err(BtmError::NoSnapshots)
+        ));
+    }
+
+    #[test]
+    fn outdated_fair() {
+        let c = cfg(SnapAlgo::Fair, 10, 3);
+        assert_eq!(vec![15, 5], outdated(&c, SNAPS).unwrap());
+        let c = cfg(SnapAlgo::Fair, 10, 5);
+        assert!(outdated(&c, SNAPS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn outdated_fade() {
+        // 2 snapshots in each chunk, the n-th chunk keeps the multiples of `itv^n`,
+        // counted backwards from `u64::MAX`, which ends with `615`
+        let c = cfg(SnapAlgo::Fade, 10, 20);
+        let snaps = [135, 125, 115, 105, 15, 5];
+        assert_eq!(vec![105, 15, 5], outdated(&c, &snaps).unwrap());
+
+        // the cap still applies
+        let c = cfg(SnapAlgo::Fade, 10, 10);
+        let snaps = (0..12).rev().map(|i| 5 + i * 10).collect::<Vec<_>>();
+        let res = outdated(&c, &snaps).unwrap();
+        assert!(res.contains(&15) && res.contains(&5));
+
+        let c = cfg(SnapAlgo::Fade, 100_000, 20);
+        assert!(matches!(
+            outdated(&c, &snaps),
+            Err(BtmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn match_the_interval() {
+        let c = cfg(SnapAlgo::Fair, 10, 100);
+        assert!(itv_matched(&c, 5) && itv_matched(&c, 15));
+        assert!(!itv_matched(&c, 10));
+    }
+}
@@ -1,157 +1,146 @@
-use crate::{BtmCfg, SnapAlgo, STEP_CNT};
-use ruc::{cmd::exec_output, *};
+use super::exec_output;
+use crate::{BtmCfg, Result};
+use ruc::*;
+use std::{fs, path::PathBuf};
 
 #[inline(always)]
-pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
-    if sorted_snapshots(cfg).c(d!())?.contains(&idx) {
-        return Err(eg!("Snapshot {} already exists!", idx));
-    }
-
-    alt!(0 != (u64::MAX - idx) % cfg.itv, return Ok(()));
-    clean_outdated(cfg).c(d!())?;
-    let cmd = format!(
+pub(crate) fn snapshot_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!(
         "
             zfs destroy {0}@{1} 2>/dev/null;
             zfs snapshot {0}@{1}
             ",
         &cfg.volume, idx
-    );
-    exec_output(&cmd).c(d!()).map(|_| ())
+    )
 }
 
-pub(crate) fn sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
-    let cmd = format!(
-        r"zfs list -t snapshot -r {} | grep -o '@[0-9]\+' | sed 's/@//'",
+#[inline(always)]
+pub(crate) fn list_cmd(cfg: &BtmCfg) -> Result<String> {
+    Ok(format!(
+        "zfs list -H -o name -t snapshot -d 1 {}",
         &cfg.volume
-    );
-    let output = exec_output(&cmd).c(d!())?;
+    ))
+}
 
-    let mut res = output
+pub(crate) fn parse_list(cfg: &BtmCfg, output: &str) -> Result<Vec<u64>> {
+    let prefix = format!("{}@", &cfg.volume);
+    let res = output
         .lines()
-        .map(|l| l.parse::<u64>().c(d!()))
-        .collect::<Result<Vec<u64>>>()?;
-    res.sort_unstable_by(|a, b| b.cmp(a));
-    //res.dedup();
-
+        .filter_map(|l| l.strip_prefix(&prefix))
+        .filter_map(|h| h.parse::<u64>().ok())
+        .collect();
     Ok(res)
 }
 
-pub(crate) fn rollback(cfg: &BtmCfg, idx: Option<i128>, strict: bool) -> Result<()> {
-    // convert to AESC order for `binary_search`
-    let mut snaps = sorted_snapshots(cfg).c(d!())?;
-    // convert to AESC order for `binary_search`
-    snaps.reverse();
-    alt!(snaps.is_empty(), return Err(eg!("no snapshots")));
+#[inline(always)]
+pub(crate) fn rollback_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!("zfs rollback -r {}@{}", &cfg.volume, idx)
+}
 
-    let idx = idx
-        .map(|i| i as u64)
-        .unwrap_or_else(|| snaps[snaps.len() - 1]);
+#[inline(always)]
+pub(crate) fn space_used_cmd(cfg: &BtmCfg) -> String {
+    format!("zfs get -Hp -o value usedbysnapshots {}", &cfg.volume)
+}
 
-    let cmd = match snaps.binary_search(&idx) {
-        Ok(_) => {
-            format!("zfs rollback -r {}@{}", &cfg.volume, idx)
-        }
-        Err(i) => {
-            if strict {
-                return Err(eg!("specified height does not exist"));
-            } else {
-                let effective_idx = if 1 + i > snaps.len() {
-                    snaps[snaps.len() - 1]
-                } else {
-                    *(0..i)
-                        .rev()
-                        .find_map(|i| snaps.get(i))
-                        .c(d!("no snapshots found"))?
-                };
-                format!("zfs rollback -r {}@{}", &cfg.volume, effective_idx)
-            }
-        }
-    };
+#[inline(always)]
+pub(crate) fn free_space_cmd(cfg: &BtmCfg) -> String {
+    format!("zfs get -Hp -o value available {}", &cfg.volume)
+}
 
-    exec_output(&cmd).c(d!()).map(|_| ())
+#[inline(always)]
+pub(crate) fn creation_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!("zfs get -Hp -o value creation {}@{}", &cfg.volume, idx)
 }
 
 #[inline(always)]
-pub(crate) fn check(volume: &str) -> Result<()> {
-    let cmd = format!("zfs list -r {0} || zfs create {0}", volume);
-    exec_output(&cmd).c(d!()).map(|_| ())
+pub(crate) fn exists_cmd(cfg: &BtmCfg) -> String {
+    format!("zfs list -H -o name {}", &cfg.volume)
 }
 
 #[inline(always)]
-fn clean_outdated(cfg: &BtmCfg) -> Result<()> {
-    match cfg.algo {
-        SnapAlgo::Fair => clean_outdated_fair(cfg).c(d!()),
-        SnapAlgo::Fade => clean_outdated_fade(cfg).c(d!()),
+pub(crate) fn send_cmd(cfg: &BtmCfg, parent: Option<u64>, idx: u64) -> String {
+    match parent {
+        Some(p) => format!("zfs send -i @{1} {0}@{2}", &cfg.volume, p, idx),
+        None => format!("zfs send {}@{}", &cfg.volume, idx),
     }
 }
 
-fn clean_outdated_fair(cfg: &BtmCfg) -> Result<()> {
-    let snaps = sorted_snapshots(cfg).c(d!())?;
-    let cap = cfg.get_cap() as usize;
+#[inline(always)]
+pub(crate) fn recv_cmd(cfg: &BtmCfg, force: bool) -> String {
+    format!("zfs recv {}{}", alt!(force, "-F ", ""), &cfg.volume)
+}
+
+// Destroy snapshots one by one,
+// a failure will not prevent others from being destroyed
+pub(crate) fn destroy_cmds(cfg: &BtmCfg, heights: &[u64]) -> Vec<String> {
+    heights
+        .iter()
+        .map(|h| format!("zfs destroy {}@{}", &cfg.volume, h))
+        .collect()
+}
 
-    if 1 + cap > snaps.len() {
-        return Ok(());
-    }
+#[inline(always)]
+pub(crate) fn check(volume: &str) -> Result<()> {
+    let cmd = format!("zfs list -r {0} || zfs create {0}", volume);
+    exec_output(&cmd).map(|_| ())
+}
 
-    snaps[cap..].iter().for_each(|i| {
-        let cmd = format!("zfs destroy {}@{}", &cfg.volume, i);
-        info_omit!(exec_output(&cmd));
+// The mount point of the volume, `None` if it is not mounted
+pub(crate) fn mountpoint(cfg: &BtmCfg) -> Result<Option<PathBuf>> {
+    let mounts = fs::read_to_string("/proc/mounts").c(d!())?;
+    let mp = mounts.lines().find_map(|l| {
+        let mut fields = l.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(dev), Some(dir), Some("zfs")) if dev == cfg.volume => {
+                Some(PathBuf::from(dir.replace("\\040", " ")))
+            }
+            _ => None,
+        }
     });
+    Ok(mp)
+}
 
-    Ok(())
-}
-
-// Logical steps:
-//
-// 1. clean up outdated snapshot in each chunks
-// > # Example
-// > - itv = 10
-// > - cap = 100
-// > - step_cnt = 5
-// > - chunk_size = 100 / 5 = 20
-// >
-// > blocks cover = chunk_size * (itv^1 + itv^2 ... itv^step_cnt)
-// >              = 55_5500
-// >
-// > this means we can use 100 snapshots to cover 55_5500 blocks
-//
-// 2. clean up snapshot whose indexs exceed `cap`
-fn clean_outdated_fade(cfg: &BtmCfg) -> Result<()> {
-    let snaps = sorted_snapshots(cfg).c(d!())?;
-    let cap = cfg.get_cap() as usize;
-
-    cfg.check().c(d!())?;
-    let chunk_size = cap / STEP_CNT;
-    let chunk_denominators = (0..STEP_CNT as u32).map(|n| cfg.itv.pow(1 + n));
-
-    if 1 + chunk_size > snaps.len() {
-        return Ok(());
-    }
+// Mounted automatically on access
+pub(crate) fn snapshot_path(cfg: &BtmCfg, idx: u64) -> Result<PathBuf> {
+    let mp = mountpoint(cfg)?.c(d!("{} is not mounted", &cfg.volume))?;
+    Ok(mp.join(".zfs/snapshot").join(idx.to_string()))
+}
 
-    // 1.
-    let mut pair = (&snaps[..0], &snaps[..]);
-    for denominator in chunk_denominators {
-        pair = if chunk_size < pair.1.len() {
-            pair.1.split_at(chunk_size)
-        } else {
-            (pair.1, &[])
-        };
-
-        pair.0.iter().for_each(|n| {
-            if 0 != (u64::MAX - n) % denominator {
-                let cmd = format!("zfs destroy {}@{}", &cfg.volume, n);
-                info_omit!(exec_output(&cmd));
-            }
-        });
-    }
+#[inline(always)]
+pub(crate) fn pool_status_cmd(cfg: &BtmCfg) -> String {
+    let pool = cfg.volume.split('/').next().unwrap_or_default();
+    format!("zpool status -v {}", pool)
+}
+
+// Healthy if `zpool status` reports no known data errors,
+// the summary is the result of the last scrub
+pub(crate) fn parse_pool_status(cfg: &BtmCfg, idx: u64, output: &str) -> (bool, String) {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|l| l.trim().strip_prefix(name))
+            .map(|v| v.trim().to_owned())
+    };
+    let state = field("state:").unwrap_or_else(|| "UNKNOWN".to_owned());
+    let scan = field("scan:").unwrap_or_else(|| "none requested".to_owned());
+    let errors = field("errors:").unwrap_or_default();
 
-    // 2.
-    if cap < snaps.len() {
-        snaps[cap..].iter().for_each(|i| {
-            let cmd = format!("zfs destroy {}@{}", &cfg.volume, i);
-            info_omit!(exec_output(&cmd));
-        });
+    if "No known data errors" == errors {
+        return (true, format!("{}, scan: {}", state, scan));
     }
 
-    Ok(())
+    // files are listed as `<dataset>@<snapshot>:<path>` if not mounted
+    let prefix = format!("{}@{}:", &cfg.volume, idx);
+    let files = output
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix(&prefix))
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        (false, format!("{}, errors: {}", state, errors))
+    } else {
+        (
+            false,
+            format!("{}, damaged files: {}", state, files.join(", ")),
+        )
+    }
 }
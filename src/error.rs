@@ -0,0 +1,152 @@
+//!
+//! # Errors of the library API
+//!
+//! All public functions return a [`BtmError`],
+//! which can still be converted into a `ruc` error by `?` or `.c(d!())`.
+//!
+
+use crate::SnapMode;
+use ruc::*;
+use std::{error::Error, fmt, path::PathBuf, result::Result as StdResult};
+
+/// Result type of the public API
+pub type Result<T> = StdResult<T, BtmError>;
+
+/// All failure paths of btm
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BtmError {
+    /// The snapshot of this height already exists
+    SnapshotExists(u64),
+    /// There are no snapshots at all
+    NoSnapshots,
+    /// The specified height does not exist, in the `strict` mode
+    HeightNotFound(u64),
+    /// The snapshot is pinned, it can not be destroyed
+    Pinned(u64),
+    /// The operation can not be performed in this mode
+    Unsupported {
+        /// The mode in use
+        mode: SnapMode,
+        /// The refused operation
+        op: &'static str,
+    },
+    /// Invalid configurations
+    InvalidConfig(String),
+    /// A required command, eg. `zfs` or `btrfs`, is not installed
+    ToolMissing(String),
+    /// An external command exited with a failure
+    Command {
+        /// The command line
+        cmd: String,
+        /// The standard error output of the command
+        stderr: String,
+    },
+    /// The pre-rollback hook failed, and the rollback has been aborted
+    PreHookFailed(String),
+    /// The post-rollback hook failed
+    PostHookFailed(String),
+    /// The volume is still in use by some processes
+    Busy {
+        /// The mount point of the volume
+        path: PathBuf,
+        /// `pid(command name)` of each process
+        holders: Vec<String>,
+    },
+    /// The volume is locked by another `btm` operation
+    Locked {
+        /// Who is holding the lock
+        pid: String,
+        /// What the holder is doing
+        op: String,
+    },
+    /// Too many heights are waiting in the queue, try again later
+    QueueFull,
+    /// The daemon can not be reached, it may be not running
+    DaemonUnreachable(String),
+    /// The daemon did not respond in time
+    DaemonTimeout,
+    /// The daemon reported a failure
+    DaemonFailure(String),
+    /// The socket of the daemon can not be written,
+    /// or the daemon refused the caller, which is not an allowed peer
+    PermissionDenied(String),
+    /// The height has been coalesced into a later one by the daemon,
+    /// its own state is not captured
+    Coalesced {
+        /// The requested height
+        idx: u64,
+        /// The later height whose snapshot is created instead
+        into: u64,
+    },
+    /// The backup store is broken, or does not match the volume
+    InvalidStore(String),
+    /// The archive is broken, eg. a file does not match its checksum
+    InvalidArchive(String),
+    /// A chunk of a state-sync snapshot does not match its hash,
+    /// it can be fetched again from another peer
+    InvalidChunk(u32),
+    /// Errors from the OS or other libraries
+    Other(Box<dyn RucError>),
+}
+
+impl fmt::Display for BtmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SnapshotExists(h) => write!(f, "Snapshot {} already exists!", h),
+            Self::NoSnapshots => write!(f, "no snapshots"),
+            Self::HeightNotFound(h) => {
+                write!(f, "specified height does not exist: {}", h)
+            }
+            Self::Pinned(h) => write!(f, "snapshot {} is pinned", h),
+            Self::Unsupported { mode, op } => {
+                write!(f, "`{}` is not supported in `{}` mode", op, mode)
+            }
+            Self::InvalidConfig(e) => write!(f, "invalid config: {}", e),
+            Self::ToolMissing(e) => write!(f, "command not found: {}", e),
+            Self::Command { cmd, stderr } => {
+                write!(f, "command failed: {}, stderr: {}", cmd.trim(), stderr)
+            }
+            Self::PreHookFailed(e) => {
+                write!(f, "pre-rollback hook failed, rollback aborted: {}", e)
+            }
+            Self::PostHookFailed(e) => write!(f, "post-rollback hook failed: {}", e),
+            Self::Busy { path, holders } => write!(
+                f,
+                "{} is busy, still in use by: {}",
+                path.display(),
+                holders.join(", ")
+            ),
+            Self::Locked { pid, op } => write!(f, "locked by pid {} doing {}", pid, op),
+            Self::QueueFull => write!(f, "the queue is full"),
+            Self::DaemonUnreachable(e) => write!(f, "daemon is unreachable: {}", e),
+            Self::DaemonTimeout => write!(f, "timeout while waiting for the daemon"),
+            Self::DaemonFailure(e) => write!(f, "daemon reported a failure: {}", e),
+            Self::PermissionDenied(e) => write!(f, "permission denied: {}", e),
+            Self::Coalesced { idx, into } => {
+                write!(f, "height {} has been coalesced into {}", idx, into)
+            }
+            Self::InvalidStore(e) => write!(f, "invalid backup store: {}", e),
+            Self::InvalidArchive(e) => write!(f, "invalid archive: {}", e),
+            Self::InvalidChunk(i) => write!(f, "chunk {} does not match its hash", i),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for BtmError {}
+
+impl From<Box<dyn RucError>> for BtmError {
+    fn from(e: Box<dyn RucError>) -> Self {
+        Self::Other(e)
+    }
+}
+
+impl From<BtmError> for Box<dyn RucError> {
+    fn from(e: BtmError) -> Self {
+        match e {
+            BtmError::Other(e) => e,
+            e => eg!(e),
+        }
+    }
+}
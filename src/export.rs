@@ -0,0 +1,241 @@
+//!
+//! # Backup store of snapshot streams
+//!
+//! Snapshots are exported as `zfs send` or `btrfs send` streams into a directory,
+//! usually on another pool or disk, so that a failure of the pool loses nothing.
+//!
+//! The first stream of a chain is a full one,
+//! each of the others is incremental from the previous height;
+//! a new chain is started once the base of the next stream has been cleaned up.
+//! `manifest.json` in the directory records all streams in the order of export.
+//!
+
+use crate::{audit, driver, BtmCfg, BtmError, Result, SnapMode};
+use nix::fcntl::{Flock, FlockArg};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the format of the manifest
+const MANIFEST_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+
+/// Writers of the same store are serialized by this file
+const LOCK: &str = ".lock";
+
+/// Contents of a backup store
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    /// Version of the format
+    pub version: u32,
+    /// The exported volume
+    pub volume: String,
+    /// Zfs or Btrfs, streams can only be imported in the same mode
+    pub mode: SnapMode,
+    /// All streams, in the order of export
+    pub streams: Vec<Stream>,
+}
+
+/// A `zfs send` or `btrfs send` stream of a snapshot
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Stream {
+    /// Height of the snapshot
+    pub height: u64,
+    /// Incremental from this height, `None` means a full stream
+    pub parent: Option<u64>,
+    /// File name in the store
+    pub file: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Unix timestamp of the export
+    pub ts: u64,
+}
+
+impl Manifest {
+    /// Load the manifest of a store
+    pub fn load(dir: &Path) -> Result<Self> {
+        let m: Self = fs::read(dir.join(MANIFEST))
+            .c(d!())
+            .and_then(|b| serde_json::from_slice(&b).c(d!()))
+            .map_err(|e| invalid(dir, e.get_lowest_msg()))?;
+        if m.version > MANIFEST_VERSION {
+            return Err(invalid(
+                dir,
+                format!("unsupported manifest version: {}", m.version),
+            ));
+        }
+        Ok(m)
+    }
+
+    // Write to a temporary file at first, and then rename it
+    fn save(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_vec_pretty(self).c(d!())?).c(d!())?;
+        fs::rename(tmp, dir.join(MANIFEST))
+            .c(d!())
+            .map_err(From::from)
+    }
+
+    /// The streams to import in order to restore `height`
+    pub fn chain(&self, height: u64) -> Result<Vec<&Stream>> {
+        // a later stream of the same height wins
+        let streams = self
+            .streams
+            .iter()
+            .map(|s| (s.height, s))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut chain = vec![];
+        let mut next = Some(height);
+        while let Some(h) = next {
+            let s = streams.get(&h).copied().ok_or_else(|| {
+                alt!(
+                    h == height,
+                    BtmError::HeightNotFound(h),
+                    BtmError::InvalidStore(format!("the stream of {} is missing", h))
+                )
+            })?;
+            chain.push(s);
+            next = s.parent;
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+}
+
+/// Export snapshots that are not in the store yet, see the module doc
+pub(crate) fn export(cfg: &BtmCfg, dir: &Path) -> Result<Vec<Stream>> {
+    fs::create_dir_all(dir).c(d!())?;
+    let _lk = lock(dir, "an export into")?;
+
+    let mut m = match Manifest::load(dir) {
+        Ok(m) => m,
+        Err(_) if !dir.join(MANIFEST).exists() => Manifest {
+            version: MANIFEST_VERSION,
+            volume: cfg.volume.clone(),
+            mode: cfg.mode,
+            streams: vec![],
+        },
+        Err(e) => return Err(e),
+    };
+    if m.volume != cfg.volume || m.mode != cfg.mode {
+        return Err(invalid(
+            dir,
+            format!("it belongs to {} in the {} mode", m.volume, m.mode),
+        ));
+    }
+
+    let mut snaps = driver::sorted_snapshots(cfg)?;
+    snaps.reverse();
+
+    let last = m.streams.last().map(|s| s.height);
+    // the base of an incremental stream must exist on both sides
+    let mut parent = last.filter(|h| snaps.contains(h));
+
+    let mut exported = vec![];
+    for h in snaps.into_iter().filter(|h| Some(*h) > last) {
+        let file = match parent {
+            Some(p) => format!("{}-{}.incr", p, h),
+            None => format!("{}.full", h),
+        };
+        let tmp = dir.join(format!("{}.tmp", file));
+        driver::send(cfg, parent, h, &tmp)?;
+        let size = fs::metadata(&tmp).c(d!())?.len();
+        fs::rename(&tmp, dir.join(&file)).c(d!())?;
+
+        let s = Stream {
+            height: h,
+            parent,
+            file,
+            size,
+            ts: now(),
+        };
+        // saved one by one, so an interrupted export can be resumed
+        m.streams.push(s.clone());
+        m.save(dir)?;
+
+        exported.push(s);
+        parent = Some(h);
+    }
+
+    Ok(exported)
+}
+
+/// Restore the chain of `height`, the latest one by default,
+/// into a volume that does not exist yet
+pub(crate) fn import(cfg: &BtmCfg, dir: &Path, height: Option<u64>) -> Result<u64> {
+    let m = Manifest::load(dir)?;
+    if m.mode != cfg.mode {
+        return Err(invalid(
+            dir,
+            format!("it is exported in the {} mode", m.mode),
+        ));
+    }
+    if driver::exists(cfg)? {
+        return Err(BtmError::InvalidConfig(format!(
+            "{} already exists, streams can only be imported into a new volume",
+            cfg.volume
+        )));
+    }
+
+    let height = match height {
+        Some(h) => h,
+        None => m
+            .streams
+            .iter()
+            .map(|s| s.height)
+            .max()
+            .ok_or(BtmError::NoSnapshots)?,
+    };
+
+    let mut rec = audit::start(cfg, "import", Some(height));
+    let res = m.chain(height).and_then(|chain| {
+        for s in chain {
+            driver::recv(
+                cfg,
+                &m.volume,
+                s.height,
+                s.parent.is_none(),
+                &dir.join(&s.file),
+            )?;
+        }
+        driver::recv_done(cfg, height)
+    });
+    rec.resolved = res.as_ref().ok().map(|_| height);
+    audit::finish(cfg, rec, &res);
+    res.map(|_| height)
+}
+
+/// Lock the store `dir` exclusively, `op` is reported to others
+pub(crate) fn lock(dir: &Path, op: &str) -> Result<Flock<fs::File>> {
+    let f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK))
+        .c(d!())?;
+    Flock::lock(f, FlockArg::LockExclusiveNonblock).map_err(|(_, e)| match e {
+        nix::errno::Errno::EWOULDBLOCK => BtmError::Locked {
+            pid: "-".to_owned(),
+            op: format!("{} {}", op, dir.display()),
+        },
+        e => eg!(e).into(),
+    })
+}
+
+fn invalid(dir: &Path, msg: impl Into<String>) -> BtmError {
+    BtmError::InvalidStore(format!("{}: {}", dir.display(), msg.into()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,88 @@
+//!
+//! # Hooks around a rollback
+//!
+//! Rolling back the live data volume under a running node
+//! will corrupt its state, so the node should be stopped first,
+//! and be started again after the rollback finished.
+//!
+
+use crate::{driver::exec_output, BtmError, Result};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// Commands to be executed around a `rollback`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Hooks {
+    /// Executed before a `rollback`, eg. `systemctl stop <unit>`,
+    /// the rollback will be aborted if it fails
+    pub pre_rollback: Option<String>,
+    /// Executed after a `rollback`, eg. `systemctl start <unit>`
+    pub post_rollback: Option<String>,
+}
+
+impl Hooks {
+    /// Stop the systemd `unit` before a rollback,
+    /// and start it again after the rollback
+    pub fn systemd(unit: &str) -> Self {
+        Self {
+            pre_rollback: Some(format!("systemctl stop {}", unit)),
+            post_rollback: Some(format!("systemctl start {}", unit)),
+        }
+    }
+
+    pub(crate) fn run_pre_rollback(&self) -> Result<()> {
+        run(self.pre_rollback.as_deref()).map_err(|e| BtmError::PreHookFailed(e.to_string()))
+    }
+
+    pub(crate) fn run_post_rollback(&self) -> Result<()> {
+        run(self.post_rollback.as_deref()).map_err(|e| BtmError::PostHookFailed(e.to_string()))
+    }
+}
+
+#[inline(always)]
+fn run(hook: Option<&str>) -> Result<()> {
+    if let Some(cmd) = hook {
+        exec_output(cmd).map(|_| ())
+    } else {
+        Ok(())
+    }
+}
+
+/// Make sure that no process is holding any file handle under `path`,
+/// including its current working directory.
+pub(crate) fn check_busy(path: &Path) -> Result<()> {
+    let myself = std::process::id().to_string();
+    let mut holders = vec![];
+
+    for entry in fs::read_dir("/proc").c(d!())?.flatten() {
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if pid.parse::<u32>().is_err() || pid == myself {
+            continue;
+        }
+
+        let proc_dir = entry.path();
+        let cwd = fs::read_link(proc_dir.join("cwd")).into_iter();
+        let fds = fs::read_dir(proc_dir.join("fd"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|fd| fs::read_link(fd.path()).ok());
+
+        if cwd.chain(fds).any(|p| p.starts_with(path)) {
+            let comm = fs::read_to_string(proc_dir.join("comm")).unwrap_or_default();
+            holders.push(format!("{}({})", pid, comm.trim()));
+        }
+    }
+
+    if holders.is_empty() {
+        Ok(())
+    } else {
+        Err(BtmError::Busy {
+            path: path.to_owned(),
+            holders,
+        })
+    }
+}
@@ -0,0 +1,85 @@
+//!
+//! # In-memory snapshot index
+//!
+//! Listing snapshots is expensive when there are thousands of them,
+//! so the daemon keeps an index that is updated along with its own operations,
+//! and reconciled with the filesystem periodically,
+//! in case that snapshots are changed by others.
+//!
+
+use crate::{driver, BtmCfg, Result};
+use std::{
+    collections::BTreeSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Reconcile with the filesystem at this interval
+const RECONCILE_ITV: Duration = Duration::from_secs(60);
+
+pub(crate) struct SnapIndex {
+    cfg: BtmCfg,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    snaps: BTreeSet<u64>,
+    synced_at: Instant,
+}
+
+impl SnapIndex {
+    pub(crate) fn load(cfg: &BtmCfg) -> Result<Self> {
+        let snaps = driver::sorted_snapshots(cfg)?.into_iter().collect();
+        Ok(Self {
+            cfg: cfg.clone(),
+            inner: Mutex::new(Inner {
+                snaps,
+                synced_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Reload from the filesystem
+    pub(crate) fn refresh(&self) -> Result<()> {
+        let snaps = driver::sorted_snapshots(&self.cfg)?.into_iter().collect();
+        let mut inner = self.inner.lock().unwrap();
+        inner.snaps = snaps;
+        inner.synced_at = Instant::now();
+        Ok(())
+    }
+
+    /// All snapshots in 'DESC' order
+    pub(crate) fn sorted(&self) -> Result<Vec<u64>> {
+        if RECONCILE_ITV < self.inner.lock().unwrap().synced_at.elapsed() {
+            self.refresh()?;
+        }
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .snaps
+            .iter()
+            .rev()
+            .copied()
+            .collect())
+    }
+
+    pub(crate) fn insert(&self, idx: u64) {
+        self.inner.lock().unwrap().snaps.insert(idx);
+    }
+
+    pub(crate) fn remove(&self, heights: &[u64]) {
+        let mut inner = self.inner.lock().unwrap();
+        heights.iter().for_each(|h| {
+            inner.snaps.remove(h);
+        });
+    }
+}
+
+/// All snapshots in 'DESC' order, listed from the filesystem if there is no index
+pub(crate) fn snapshots(cfg: &BtmCfg, index: Option<&SnapIndex>) -> Result<Vec<u64>> {
+    match index {
+        Some(i) => i.sorted(),
+        None => driver::sorted_snapshots(cfg),
+    }
+}
@@ -10,14 +10,49 @@
 #![deny(warnings)]
 #![deny(missing_docs)]
 
+#[cfg(feature = "async")]
+pub mod aio;
 mod api;
+mod archive;
+mod audit;
 mod driver;
+mod error;
+mod export;
+mod hook;
+mod index;
+mod lock;
+mod logging;
+mod metrics;
+mod pin;
+mod replica;
+mod stale;
+mod state;
+mod statesync;
+mod verify;
+mod worker;
 
-pub use api::server::run_daemon;
+pub use api::{
+    client::{AckMode, ClientCfg, DaemonClient},
+    model::DaemonStatus,
+    server::{run_daemon, run_daemon_with_config, Peers},
+};
+pub use archive::{ArchiveFile, ArchiveManifest};
+pub use audit::AuditRecord;
+pub use error::{BtmError, Result};
+pub use export::{Manifest, Stream};
+pub use hook::Hooks;
+pub use logging::{LogCfg, LogFormat, LogLevel};
+pub use replica::ReplicaCfg;
+pub use stale::StaleCfg;
+pub use statesync::{SyncRestore, SyncSnapshot, SyncStore, DEFAULT_CHUNK_SIZE, SYNC_FORMAT};
+pub use verify::{Check, CheckStatus, VerifyCfg, VerifyReport};
+pub use worker::{SnapStatus, SnapWorker};
 
 use driver::{btrfs, external, zfs};
-use ruc::{cmd, *};
-use std::{fmt, result::Result as StdResult, str::FromStr};
+use lock::VolumeLock;
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path, result::Result as StdResult, str::FromStr};
 
 /// Maximum number of snapshots that can be kept
 pub const CAP_MAX: u64 = 4096;
@@ -26,8 +61,19 @@ pub const CAP_MAX: u64 = 4096;
 /// only useful within the `SnapAlgo::Fade` algo
 pub const STEP_CNT: usize = 10;
 
-/// Configures of snapshot mgmt
-#[derive(Clone, Debug)]
+/// Configures of snapshot mgmt,
+/// can also be loaded from a config file, see [BtmCfg::from_file]
+///
+/// New fields may be added in minor versions, here and in its config structs,
+/// so create it by [BtmCfg::new] or `Default`, and then set the fields:
+///
+/// ```ignore
+/// let mut cfg = BtmCfg::new("zroot/data", Some("zfs"))?;
+/// cfg.algo = SnapAlgo::Fade;
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
 pub struct BtmCfg {
     /// The interval between adjacent snapshots, default to 10 blocks
     pub itv: u64,
@@ -41,74 +87,292 @@ pub struct BtmCfg {
     pub algo: SnapAlgo,
     /// A data volume containing all blockchain data
     pub volume: String,
+    /// Commands to be executed around a `rollback`, eg. stop/start the node
+    pub hooks: Hooks,
+    /// How many seconds to wait for the lock of the volume, default to 10
+    pub lock_timeout: u64,
+    /// Address of the daemon, derived from the `volume` if missing,
+    /// an absolute path means a socket file;
+    /// if missing, the daemon also listens on the address of the first generation
+    pub socket: Option<String>,
+    /// Who can send requests to the daemon
+    pub peers: Peers,
+    /// How to talk to the daemon in the `External` mode,
+    /// not loaded from config files
+    #[serde(skip)]
+    pub client: ClientCfg,
+    /// How many heights can be waiting in the queue of a [SnapWorker],
+    /// default to 100
+    pub queue_size: usize,
+    /// Where `btm daemon` serves the Prometheus metrics, disabled if missing,
+    /// eg. `127.0.0.1:9185`, an absolute path means a unix socket
+    pub metrics: Option<String>,
+    /// Level and format of log events, only applied by `btm daemon`,
+    /// see [LogCfg::apply] for others
+    pub log: LogCfg,
+    /// When `btm daemon` is considered stale, and how to alert,
+    /// disabled by default
+    pub stale: StaleCfg,
+    /// Where `btm daemon` replicates new snapshots to, disabled by default
+    pub replica: ReplicaCfg,
+    /// How snapshots are verified, see [BtmCfg::verify]
+    pub verify: VerifyCfg,
+}
+
+impl Default for BtmCfg {
+    fn default() -> Self {
+        Self {
+            itv: 10,
+            cap: 100,
+            cap_clean_kept: 0,
+            mode: SnapMode::default(),
+            algo: SnapAlgo::default(),
+            volume: String::new(),
+            hooks: Hooks::default(),
+            lock_timeout: 10,
+            socket: None,
+            peers: Peers::default(),
+            client: ClientCfg::default(),
+            queue_size: 100,
+            metrics: None,
+            log: LogCfg::default(),
+            stale: StaleCfg::default(),
+            replica: ReplicaCfg::default(),
+            verify: VerifyCfg::default(),
+        }
+    }
 }
 
 impl BtmCfg {
     // Check mistakes
     fn check(&self) -> Result<()> {
-        self.itv.checked_pow(STEP_CNT as u32).c(d!()).map(|_| ())
+        self.itv
+            .checked_pow(STEP_CNT as u32)
+            .ok_or_else(|| BtmError::InvalidConfig(format!("`itv` is too large: {}", self.itv)))?;
+        self.replica.check(&self.volume)
     }
 
     /// Create a simple instance
     #[inline(always)]
     pub fn new(volume: &str, mode: Option<&str>) -> Result<Self> {
         let mode = if let Some(m) = mode {
-            SnapMode::from_str(m).map_err(|e| eg!(e))?
+            SnapMode::from_string(m)?
         } else {
-            SnapMode::guess(volume).c(d!())?
+            SnapMode::guess(volume)?
         };
         Ok(Self {
-            itv: 10,
-            cap: 100,
-            cap_clean_kept: 0,
             mode,
-            algo: SnapAlgo::Fair,
             volume: volume.to_owned(),
+            ..Default::default()
         })
     }
 
+    /// Load from a TOML file, or a JSON file if its name ends with `.json`,
+    /// missing fields are set to their default values, unknown ones are rejected.
+    ///
+    /// ```toml
+    /// volume = "zroot/data"
+    /// mode = "zfs"
+    /// itv = 10
+    /// cap = 100
+    ///
+    /// [hooks]
+    /// pre_rollback = "systemctl stop my-node"
+    /// post_rollback = "systemctl start my-node"
+    /// ```
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::load_file(path).map(|(cfg, _)| cfg)
+    }
+
+    // Like `from_file`, but also return whether `mode` is set,
+    // it is `External` by default, which is not a valid one for the daemon
+    pub(crate) fn load_file(path: &Path) -> Result<(Self, bool)> {
+        let content = fs::read_to_string(path).c(d!())?;
+        let invalid = |e: &dyn fmt::Display| BtmError::InvalidConfig(e.to_string());
+        let (cfg, has_mode): (Self, bool) = if path.extension().is_some_and(|e| e == "json") {
+            let v = serde_json::from_str::<serde_json::Value>(&content).map_err(|e| invalid(&e))?;
+            let has_mode = v.get("mode").is_some();
+            (
+                serde_json::from_value(v).map_err(|e| invalid(&e))?,
+                has_mode,
+            )
+        } else {
+            let v = toml::from_str::<toml::Table>(&content).map_err(|e| invalid(&e))?;
+            let has_mode = v.contains_key("mode");
+            (v.try_into().map_err(|e| invalid(&e))?, has_mode)
+        };
+
+        if cfg.volume.is_empty() {
+            return Err(BtmError::InvalidConfig("`volume` is missing".to_owned()));
+        }
+        cfg.check().map(|_| (cfg, has_mode))
+    }
+
+    /// The address that the daemon of this volume listens on
+    pub fn daemon_socket(&self) -> String {
+        self.socket
+            .clone()
+            .unwrap_or_else(|| api::model::default_socket(&self.volume))
+    }
+
+    /// A client of the daemon of this volume
+    pub fn daemon_client(&self) -> DaemonClient {
+        DaemonClient::new(&self.daemon_socket()).config(self.client.clone())
+    }
+
     /// Generate a snapshot for the latest state of blockchain
     #[inline(always)]
     pub fn snapshot(&self, idx: u64) -> Result<()> {
+        // the lock is held by the daemon in `External` mode
+        if let SnapMode::External = self.mode {
+            return external::gen_snapshot(self, idx);
+        }
+
+        let _lk = self.lock(&format!("snapshot {}", idx))?;
+
         // sync data to disk before snapshoting
         nix::unistd::sync();
 
-        match self.mode {
-            SnapMode::Zfs => zfs::gen_snapshot(self, idx).c(d!()),
-            SnapMode::Btrfs => btrfs::gen_snapshot(self, idx).c(d!()),
-            SnapMode::External => external::gen_snapshot(self, idx).c(d!()),
-        }
+        driver::gen_snapshot(self, idx)
+    }
+
+    /// Create snapshots in a background thread,
+    /// see [SnapWorker] for the details.
+    ///
+    /// ```ignore
+    /// let worker = cfg.spawn_worker()?;
+    /// worker.submit(1000)?;
+    /// assert_eq!(Some(SnapStatus::Done), worker.wait(1000));
+    /// ```
+    pub fn spawn_worker(&self) -> Result<SnapWorker> {
+        SnapWorker::spawn(self.clone(), None, None)
     }
 
     /// Rollback the state of blockchain to a specificed height
-    #[inline(always)]
+    ///
+    /// The `pre_rollback` hook is executed at first,
+    /// and the rollback will be aborted if it fails;
+    /// the `post_rollback` hook will always be executed
+    /// once the `pre_rollback` hook has succeeded.
     pub fn rollback(&self, idx: Option<i128>, strict: bool) -> Result<()> {
-        match self.mode {
-            SnapMode::Zfs => zfs::rollback(self, idx, strict).c(d!()),
-            SnapMode::Btrfs => btrfs::rollback(self, idx, strict).c(d!()),
-            SnapMode::External => Err(eg!("please use the `btm` tool in `External` mode")),
+        self.rollback_to(idx, strict).and_then(|(_, post)| post)
+    }
+
+    // Like `rollback`, but also return the height rolled back to,
+    // along with the result of the `post_rollback` hook
+    pub(crate) fn rollback_to(&self, idx: Option<i128>, strict: bool) -> Result<(u64, Result<()>)> {
+        self.refuse_external("rollback")?;
+
+        let _lk = self.lock("rollback")?;
+
+        let target = idx.and_then(|i| u64::try_from(i).ok());
+        let mut rec = audit::start(self, "rollback", target);
+
+        if let Err(e) = self.hooks.run_pre_rollback() {
+            let res = Err(e);
+            audit::finish(self, rec, &res);
+            return res;
+        }
+
+        let res = self
+            .check_busy()
+            .and_then(|_| driver::rollback(self, idx, strict))
+            .map(|(resolved, destroyed)| {
+                rec.resolved = Some(resolved);
+                rec.destroyed = destroyed;
+                resolved
+            });
+
+        let post_res = self.hooks.run_post_rollback();
+        match res {
+            Ok(h) => {
+                audit::finish(self, rec, &post_res);
+                Ok((h, post_res))
+            }
+            Err(e) => {
+                let res = Err(e);
+                audit::finish(self, rec, &res);
+                res
+            }
         }
     }
 
+    // Refuse to touch the volume if it is still in use
+    fn check_busy(&self) -> Result<()> {
+        if let Some(mp) = driver::mountpoint(self)? {
+            hook::check_busy(&mp)?;
+        }
+        Ok(())
+    }
+
     /// Get snapshot list in 'DESC' order.
     #[inline(always)]
     pub fn get_sorted_snapshots(&self) -> Result<Vec<u64>> {
-        match self.mode {
-            SnapMode::Zfs => zfs::sorted_snapshots(self).c(d!()),
-            SnapMode::Btrfs => btrfs::sorted_snapshots(self).c(d!()),
-            SnapMode::External => Err(eg!("please use `btm` tool in `External` mode")),
+        driver::sorted_snapshots(self)
+    }
+
+    // Local operations can not be performed in `External` mode,
+    // please use the `btm` tool instead
+    #[inline(always)]
+    fn refuse_external(&self, op: &'static str) -> Result<()> {
+        if let SnapMode::External = self.mode {
+            Err(BtmError::Unsupported {
+                mode: self.mode,
+                op,
+            })
+        } else {
+            Ok(())
         }
     }
 
+    // Take the exclusive lock of the volume for a mutating operation
+    #[inline(always)]
+    fn lock(&self, op: &str) -> Result<VolumeLock> {
+        VolumeLock::acquire(&self.volume, op, self.lock_timeout)
+    }
+
     #[inline(always)]
     fn get_cap(&self) -> u64 {
         alt!(self.cap > CAP_MAX, CAP_MAX, self.cap)
     }
 
+    /// Protect a snapshot from being cleaned up
+    pub fn pin(&self, idx: u64) -> Result<()> {
+        self.refuse_external("pin")?;
+        let _lk = self.lock("pin")?;
+        if !self.get_sorted_snapshots()?.contains(&idx) {
+            return Err(BtmError::HeightNotFound(idx));
+        }
+        let mut pins = pin::load(self)?;
+        pins.insert(idx);
+        let rec = audit::start(self, "pin", Some(idx));
+        let res = pin::save(self, &pins);
+        audit::finish(self, rec, &res);
+        res
+    }
+
+    /// Cancel the protection of a snapshot
+    pub fn unpin(&self, idx: u64) -> Result<()> {
+        self.refuse_external("unpin")?;
+        let _lk = self.lock("unpin")?;
+        let mut pins = pin::load(self)?;
+        pins.remove(&idx);
+        let rec = audit::start(self, "unpin", Some(idx));
+        let res = pin::save(self, &pins);
+        audit::finish(self, rec, &res);
+        res
+    }
+
+    /// Get pinned snapshots in 'DESC' order.
+    pub fn get_pinned(&self) -> Result<Vec<u64>> {
+        self.refuse_external("pinned")?;
+        Ok(pin::load(self)?.into_iter().rev().collect())
+    }
+
     /// List all existing snapshots.
     pub fn list_snapshots(&self) -> Result<()> {
         println!("Available snapshots are listed below:");
-        self.get_sorted_snapshots().c(d!()).map(|list| {
+        self.get_sorted_snapshots().map(|list| {
             list.into_iter().rev().for_each(|h| {
                 println!("    {}", h);
             })
@@ -117,22 +381,97 @@ impl BtmCfg {
 
     /// Clean all existing snapshots.
     pub fn clean_snapshots(&self) -> Result<()> {
-        self.get_sorted_snapshots().c(d!()).map(|list| {
-            list.into_iter()
+        self.refuse_external("clean")?;
+        let _lk = self.lock("clean")?;
+        self.get_sorted_snapshots().map(|list| {
+            let to_del = list
+                .into_iter()
                 .skip(self.cap_clean_kept)
                 .rev()
-                .for_each(|height| {
-                    let cmd = match self.mode {
-                        SnapMode::Btrfs => {
-                            format!("btrfs subvolume delete {}@{}", &self.volume, height)
-                        }
-                        SnapMode::Zfs => format!("zfs destroy {}@{}", &self.volume, height),
-                        _ => pnk!(Err(eg!("Unsupported deriver"))),
-                    };
-                    info_omit!(cmd::exec_output(&cmd));
-                });
+                .collect::<Vec<_>>();
+            let mut rec = audit::start(self, "clean", None);
+            rec.destroyed = driver::destroy(self, &to_del);
+            audit::finish(self, rec, &Ok(()));
         })
     }
+
+    /// Inspect the volume directly without a daemon,
+    /// `uptime` and `last_error` are always `None`
+    pub fn status(&self) -> Result<DaemonStatus> {
+        DaemonStatus::collect(self, &self.get_sorted_snapshots()?)
+    }
+
+    /// Export snapshots that are not in the backup store `dir` yet,
+    /// as full or incremental `zfs send`/`btrfs send` streams,
+    /// return the exported streams, see [Manifest] for the store
+    pub fn export(&self, dir: &Path) -> Result<Vec<Stream>> {
+        self.refuse_external("export")?;
+        let _lk = self.lock("export")?;
+        export::export(self, dir)
+    }
+
+    /// Restore the snapshot `idx`, the latest one by default,
+    /// from the backup store `dir` into this volume,
+    /// the volume must not exist, and the `mode` must be the same as the exported one
+    pub fn import(&self, dir: &Path, idx: Option<u64>) -> Result<u64> {
+        self.refuse_external("import")?;
+        let _lk = self.lock("import")?;
+        export::import(self, dir, idx)
+    }
+
+    /// Pack the contents of the snapshot `idx` into a portable archive `file`,
+    /// see [ArchiveManifest] for the details
+    pub fn archive(&self, idx: u64, file: &Path) -> Result<ArchiveManifest> {
+        self.refuse_external("archive")?;
+        archive::archive(self, idx, file)
+    }
+
+    /// Verify the archive `file`, and restore it into this volume as the snapshot of its height,
+    /// the volume is created if missing, it must have neither snapshots nor data
+    pub fn restore_archive(&self, file: &Path) -> Result<ArchiveManifest> {
+        self.refuse_external("restore-archive")?;
+        let _lk = self.lock("restore-archive")?;
+        archive::restore(self, file)
+    }
+
+    /// Generate the state-sync snapshot of `idx` into `store`,
+    /// chunks of `chunk_size` bytes are served by [SyncStore::load_chunk],
+    /// the volume is locked while the snapshot is read
+    pub fn sync_snapshot(
+        &self,
+        idx: u64,
+        store: &SyncStore,
+        chunk_size: u64,
+    ) -> Result<SyncSnapshot> {
+        self.refuse_external("sync-snapshot")?;
+        let _lk = self.lock("sync-snapshot")?;
+        statesync::create(self, store, idx, chunk_size)
+    }
+
+    /// Start to restore a state-sync snapshot offered by peers into this volume,
+    /// see [SyncRestore] for the details,
+    /// the volume is created if missing, it must have neither snapshots nor data.
+    ///
+    /// `hash` is the trusted [SyncSnapshot::hash] got from outside of the peers,
+    /// eg. from the light client of the chain, the restore is refused if it differs.
+    pub fn sync_restore(&self, snapshot: SyncSnapshot, hash: &str) -> Result<SyncRestore> {
+        self.refuse_external("sync-restore")?;
+        let lk = self.lock("sync-restore")?;
+        SyncRestore::start(self, snapshot, hash, lk)
+    }
+
+    /// Check the integrity of the snapshot `idx`, the latest one by default,
+    /// see [VerifyReport::ok] for the verdict
+    pub fn verify(&self, idx: Option<u64>) -> Result<VerifyReport> {
+        self.refuse_external("verify")?;
+        verify::verify(self, idx)
+    }
+
+    /// Get all records of the audit log, the oldest first,
+    /// see [AuditRecord] for the details
+    pub fn history(&self) -> Result<Vec<AuditRecord>> {
+        audit::history(self)
+    }
 }
 
 /// # Inner Operations
@@ -152,7 +491,7 @@ impl BtmCfg {
 ///
 /// # btrfs filesystem
 /// rm -rf /btrfs/data@123456 2>/dev/null
-/// btrfs subvolume snapshot /btrfs/data /btrfs/data@123456
+/// btrfs subvolume snapshot -r /btrfs/data /btrfs/data@123456
 /// ```
 ///
 /// ## rollback
@@ -165,18 +504,22 @@ impl BtmCfg {
 /// rm -rf /btrfs/data || exit 1
 /// btrfs subvolume snapshot /btrfs/data@123456 /btrfs/data
 /// ```
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub enum SnapMode {
     /// Available on some Linux distributions and FreeBSD
     /// - Ubuntu Linux
     /// - Gentoo Linux
     /// - FreeBSD
     /// - ...
+    #[serde(alias = "zfs")]
     Zfs,
     /// Available on most Linux distributions,
     /// but its user experience is worse than zfs
+    #[serde(alias = "btrfs")]
     Btrfs,
     /// Rely on an external independent process
+    #[default]
+    #[serde(alias = "external")]
     External,
 }
 
@@ -188,7 +531,7 @@ impl SnapMode {
             "zfs" => Ok(Self::Zfs),
             "btrfs" => Ok(Self::Btrfs),
             "external" => Ok(Self::External),
-            _ => Err(eg!()),
+            _ => Err(BtmError::InvalidConfig(format!("unknown mode: {}", m))),
         }
     }
 
@@ -198,15 +541,8 @@ impl SnapMode {
     /// not suitable for the `External` mode.
     pub fn guess(volume: &str) -> Result<Self> {
         zfs::check(volume)
-            .c(d!())
             .map(|_| SnapMode::Zfs)
-            .or_else(|e| btrfs::check(volume).c(d!(e)).map(|_| SnapMode::Btrfs))
-    }
-}
-
-impl Default for SnapMode {
-    fn default() -> Self {
-        Self::External
+            .or_else(|_| btrfs::check(volume).map(|_| SnapMode::Btrfs))
     }
 }
 
@@ -224,16 +560,19 @@ impl fmt::Display for SnapMode {
 impl FromStr for SnapMode {
     type Err = String;
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
-        Self::from_string(s).c(d!()).map_err(|e| e.to_string())
+        Self::from_string(s).map_err(|e| e.to_string())
     }
 }
 
 /// Snapshot management algorithm
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub enum SnapAlgo {
     /// snapshots are saved at fixed intervals
+    #[default]
+    #[serde(alias = "fair")]
     Fair,
     /// snapshots are saved in decreasing density
+    #[serde(alias = "fade")]
     Fade,
 }
 
@@ -244,17 +583,11 @@ impl SnapAlgo {
         match m.to_lowercase().as_str() {
             "fair" => Ok(Self::Fair),
             "fade" => Ok(Self::Fade),
-            _ => Err(eg!()),
+            _ => Err(BtmError::InvalidConfig(format!("unknown algo: {}", m))),
         }
     }
 }
 
-impl Default for SnapAlgo {
-    fn default() -> Self {
-        Self::Fair
-    }
-}
-
 impl fmt::Display for SnapAlgo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let contents = match self {
@@ -268,6 +601,54 @@ impl fmt::Display for SnapAlgo {
 impl FromStr for SnapAlgo {
     type Err = String;
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
-        Self::from_string(s).c(d!()).map_err(|e| e.to_string())
+        Self::from_string(s).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn load(name: &str, content: &str) -> Result<(BtmCfg, bool)> {
+        let path = env::temp_dir().join(format!("btm-test-{}-{}", process::id(), name));
+        fs::write(&path, content).unwrap();
+        let res = BtmCfg::load_file(&path);
+        fs::remove_file(path).unwrap();
+        res
+    }
+
+    #[test]
+    fn load_the_example_config() {
+        let cfg = BtmCfg::from_file(Path::new("tools/btm.toml")).unwrap();
+        assert_eq!("zfs/blockchain", cfg.volume);
+    }
+
+    #[test]
+    fn detect_the_missing_mode() {
+        let (cfg, has_mode) = load("a.toml", "volume = \"tank/test\"").unwrap();
+        assert!(!has_mode);
+        assert_eq!(SnapMode::External, cfg.mode);
+
+        let (cfg, has_mode) = load("b.toml", "volume = \"tank/test\"\nmode = \"zfs\"").unwrap();
+        assert!(has_mode);
+        assert_eq!(SnapMode::Zfs, cfg.mode);
+
+        let (_, has_mode) = load("c.json", r#"{"volume":"tank/test","mode":"btrfs"}"#).unwrap();
+        assert!(has_mode);
+    }
+
+    #[test]
+    fn reject_unknown_fields() {
+        for (name, content) in [
+            ("d.toml", "volume = \"tank/test\"\nitvl = 10"),
+            ("e.toml", "volume = \"tank/test\"\n[hooks]\npre = \"true\""),
+            ("f.json", r#"{"volume":"tank/test","peers":{"users":[0]}}"#),
+        ] {
+            assert!(matches!(
+                load(name, content),
+                Err(BtmError::InvalidConfig(_))
+            ));
+        }
     }
 }
@@ -0,0 +1,75 @@
+//!
+//! # Cross-process locking
+//!
+//! Every mutating operation on a volume must hold its lock,
+//! or a `btm clean` may race with the snapshot/pruning of the daemon.
+//!
+
+use crate::{BtmError, Result};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+use ruc::*;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// All lock files are placed in this directory
+pub(crate) const LOCK_DIR: &str = "/run/btm";
+
+/// An advisory lock of a volume,
+/// it will be released automatically when dropped
+pub(crate) struct VolumeLock {
+    _lk: Flock<File>,
+}
+
+impl VolumeLock {
+    /// Wait at most `timeout` seconds for the lock of `volume`,
+    /// `op` is recorded in the lock file for troubleshooting.
+    pub(crate) fn acquire(volume: &str, op: &str, timeout: u64) -> Result<Self> {
+        fs::create_dir_all(LOCK_DIR).c(d!())?;
+        let path = lock_path(volume);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+
+        loop {
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .c(d!())?;
+
+            match Flock::lock(f, FlockArg::LockExclusiveNonblock) {
+                Ok(lk) => {
+                    lk.set_len(0).c(d!())?;
+                    (&*lk)
+                        .write_all(format!("{} {}", std::process::id(), op).as_bytes())
+                        .c(d!())?;
+                    return Ok(Self { _lk: lk });
+                }
+                Err((_, Errno::EWOULDBLOCK)) => {
+                    if Instant::now() >= deadline {
+                        let holder = fs::read_to_string(&path).unwrap_or_default();
+                        let (pid, op) = holder.split_once(' ').unwrap_or(("?", "?"));
+                        return Err(BtmError::Locked {
+                            pid: pid.to_owned(),
+                            op: op.to_owned(),
+                        });
+                    }
+                    sleep_ms!(100);
+                }
+                Err((_, e)) => return Err(eg!(e).into()),
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn lock_path(volume: &str) -> PathBuf {
+    PathBuf::from(LOCK_DIR).join(format!("{}.lock", volume.replace('/', "%")))
+}
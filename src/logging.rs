@@ -0,0 +1,247 @@
+//!
+//! # Structured log events
+//!
+//! Events are written to stderr as text or JSON lines,
+//! the level and the format are process-wide, see [LogCfg::apply].
+//!
+
+use crate::BtmError;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// `(unix timestamp, message)` of the last `Error` event, reported by `btm status`
+static LAST_ERROR: Mutex<Option<(u64, String)>> = Mutex::new(None);
+
+/// Events less severe than the level are dropped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Failures that need attention
+    Error,
+    /// Failures that have been omitted, eg. a failed `zfs destroy` while pruning
+    #[default]
+    Warn,
+    /// Completed operations
+    Info,
+    /// Everything else
+    Debug,
+}
+
+impl LogLevel {
+    /// Parse a level from a case insensitive name
+    pub fn from_string(s: &str) -> crate::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(BtmError::InvalidConfig(format!("unknown log level: {}", s))),
+        }
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Output format of log events
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    /// Parse a format from a case insensitive name
+    pub fn from_string(s: &str) -> crate::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(BtmError::InvalidConfig(format!(
+                "unknown log format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Configures of logging
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct LogCfg {
+    /// Default to `Warn`
+    pub level: LogLevel,
+    /// Default to `Text`
+    pub format: LogFormat,
+}
+
+impl LogCfg {
+    /// Apply to the whole process, `btm daemon` does this on start and reload
+    pub fn apply(&self) {
+        LEVEL.store(self.level as u8, Ordering::Relaxed);
+        JSON.store(matches!(self.format, LogFormat::Json), Ordering::Relaxed);
+    }
+}
+
+/// `(unix timestamp, message)` of the last error of this process
+pub(crate) fn last_error() -> Option<(u64, String)> {
+    LAST_ERROR.lock().unwrap().clone()
+}
+
+/// A log event of an operation
+#[derive(Serialize)]
+pub(crate) struct Event {
+    ts: f64,
+    level: LogLevel,
+    op: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cmd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    msg: String,
+}
+
+impl Event {
+    pub(crate) fn new(op: &'static str) -> Self {
+        Self {
+            ts: 0.0,
+            level: LogLevel::Info,
+            op,
+            volume: None,
+            height: None,
+            duration_ms: None,
+            cmd: None,
+            stderr: None,
+            msg: String::new(),
+        }
+    }
+
+    pub(crate) fn volume(mut self, volume: &str) -> Self {
+        self.volume = Some(volume.to_owned());
+        self
+    }
+
+    pub(crate) fn height(mut self, height: impl Into<Option<u64>>) -> Self {
+        self.height = height.into();
+        self
+    }
+
+    /// The time elapsed since `start`
+    pub(crate) fn since(mut self, start: Instant) -> Self {
+        self.duration_ms = Some(start.elapsed().as_millis() as u64);
+        self
+    }
+
+    pub(crate) fn error(self, msg: impl Display) {
+        self.emit(LogLevel::Error, msg)
+    }
+
+    pub(crate) fn warn(self, msg: impl Display) {
+        self.emit(LogLevel::Warn, msg)
+    }
+
+    pub(crate) fn info(self, msg: impl Display) {
+        self.emit(LogLevel::Info, msg)
+    }
+
+    pub(crate) fn debug(self, msg: impl Display) {
+        self.emit(LogLevel::Debug, msg)
+    }
+
+    /// Log a failure, the command and its stderr are extracted if any
+    pub(crate) fn fail(mut self, level: LogLevel, e: &BtmError) {
+        let msg = match e {
+            BtmError::Command { cmd, stderr } => {
+                self.cmd = Some(cmd.trim().to_owned());
+                self.stderr = Some(stderr.trim().to_owned());
+                "command failed".to_owned()
+            }
+            // the whole chain spans multiple lines
+            BtmError::Other(e) => e.get_lowest_msg(),
+            e => e.to_string(),
+        };
+        self.emit(level, msg)
+    }
+
+    fn emit(mut self, level: LogLevel, msg: impl Display) {
+        self.level = level;
+        self.msg = msg.to_string();
+        self.ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+
+        // recorded even if it is not printed
+        if let LogLevel::Error = level {
+            let mut msg = format!("{}: {}", self.op, self.msg);
+            if let Some(stderr) = self.stderr.as_ref().filter(|s| !s.is_empty()) {
+                msg = format!("{}: {}", msg, stderr);
+            }
+            *LAST_ERROR.lock().unwrap() = Some((self.ts as u64, msg));
+        }
+
+        if LEVEL.load(Ordering::Relaxed) < level as u8 {
+            return;
+        }
+
+        let line = if JSON.load(Ordering::Relaxed) {
+            serde_json::to_string(&self).unwrap_or_default()
+        } else {
+            self.to_string()
+        };
+        // logging never fails the caller
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.level, self.op)?;
+        if let Some(v) = &self.volume {
+            write!(f, " volume={}", v)?;
+        }
+        if let Some(h) = self.height {
+            write!(f, " height={}", h)?;
+        }
+        if let Some(d) = self.duration_ms {
+            write!(f, " duration={}ms", d)?;
+        }
+        if let Some(c) = &self.cmd {
+            write!(f, " cmd={:?}", c)?;
+        }
+        if let Some(s) = &self.stderr {
+            write!(f, " stderr={:?}", s)?;
+        }
+        write!(f, ": {}", self.msg)
+    }
+}
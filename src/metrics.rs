@@ -0,0 +1,377 @@
+//!
+//! # Metrics in the Prometheus text format
+//!
+//! Values are kept per volume in a global registry,
+//! updated by the drivers, the worker and the daemon,
+//! and served by `btm daemon` if an address is set in [BtmCfg::metrics].
+//!
+
+use crate::{
+    driver,
+    logging::{Event, LogLevel},
+    BtmCfg, BtmError, Result,
+};
+use ruc::*;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpListener,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the duration histogram, in seconds
+const BUCKETS: [f64; 9] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0];
+
+// name, help and the getter of a gauge
+type Gauge = (&'static str, &'static str, fn(&Volume) -> Option<f64>);
+
+static REGISTRY: Mutex<BTreeMap<String, Volume>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct Volume {
+    snapshots: Option<usize>,
+    latest: Option<u64>,
+    queue: Option<usize>,
+    space_used: Option<u64>,
+    stale: Option<bool>,
+    done_at: Option<u64>,
+    requested: Option<u64>,
+    replicated: Option<u64>,
+    ops: BTreeMap<&'static str, Op>,
+}
+
+#[derive(Default)]
+struct Op {
+    // cumulative counts of each bucket are computed when rendering
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+    failures: u64,
+}
+
+fn update(cfg: &BtmCfg, f: impl FnOnce(&mut Volume)) {
+    f(REGISTRY
+        .lock()
+        .unwrap()
+        .entry(cfg.volume.clone())
+        .or_default())
+}
+
+/// Record the duration and the result of an operation,
+/// eg. `snapshot`, `prune` or `rollback`
+pub(crate) fn observe(cfg: &BtmCfg, op: &'static str, start: Instant, ok: bool) {
+    let secs = start.elapsed().as_secs_f64();
+    update(cfg, |v| {
+        let op = v.ops.entry(op).or_default();
+        if let Some(i) = BUCKETS.iter().position(|&b| secs <= b) {
+            op.buckets[i] += 1;
+        }
+        op.count += 1;
+        op.sum += secs;
+        if !ok {
+            op.failures += 1;
+        }
+    })
+}
+
+/// Run `f` and record it by [observe]
+pub(crate) fn timed<T>(cfg: &BtmCfg, op: &'static str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    let res = f();
+    observe(cfg, op, start, res.is_ok());
+    res
+}
+
+/// The async version of [timed]
+#[cfg(feature = "async")]
+pub(crate) async fn timed_async<T>(
+    cfg: &BtmCfg,
+    op: &'static str,
+    f: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let start = Instant::now();
+    let res = f.await;
+    observe(cfg, op, start, res.is_ok());
+    res
+}
+
+/// `snaps` should be in 'DESC' order
+pub(crate) fn set_snapshots(cfg: &BtmCfg, snaps: &[u64]) {
+    update(cfg, |v| {
+        v.snapshots = Some(snaps.len());
+        v.latest = snaps.first().copied();
+    })
+}
+
+pub(crate) fn set_queue(cfg: &BtmCfg, n: usize) {
+    update(cfg, |v| v.queue = Some(n))
+}
+
+/// Only set by `btm daemon`, see [crate::stale]
+pub(crate) fn set_staleness(
+    cfg: &BtmCfg,
+    stale: bool,
+    done_at: Option<u64>,
+    requested: Option<u64>,
+) {
+    update(cfg, |v| {
+        v.stale = Some(stale);
+        v.done_at = done_at;
+        v.requested = requested;
+    })
+}
+
+pub(crate) fn set_replicated(cfg: &BtmCfg, height: Option<u64>) {
+    update(cfg, |v| v.replicated = height)
+}
+
+/// Query the space used by all snapshots of the volume,
+/// only available in the `Zfs` mode
+pub(crate) fn refresh_space_used(cfg: &BtmCfg) {
+    match driver::space_used(cfg) {
+        Ok(Some(bytes)) => update(cfg, |v| v.space_used = Some(bytes)),
+        Ok(None) => {}
+        Err(e) => Event::new("metrics")
+            .volume(&cfg.volume)
+            .fail(LogLevel::Warn, &e),
+    }
+}
+
+/// All metrics in the text exposition format
+pub(crate) fn render() -> String {
+    let reg = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+
+    let gauges: [Gauge; 8] = [
+        ("btm_snapshots", "Number of existing snapshots", |v| {
+            v.snapshots.map(|n| n as f64)
+        }),
+        (
+            "btm_latest_snapshot_height",
+            "Height of the latest snapshot",
+            |v| v.latest.map(|h| h as f64),
+        ),
+        (
+            "btm_queue_depth",
+            "Number of heights waiting in the queue",
+            |v| v.queue.map(|n| n as f64),
+        ),
+        (
+            "btm_snapshot_space_used_bytes",
+            "Space used by all snapshots",
+            |v| v.space_used.map(|n| n as f64),
+        ),
+        (
+            "btm_latest_requested_height",
+            "The highest height requested by the node",
+            |v| v.requested.map(|h| h as f64),
+        ),
+        (
+            "btm_last_snapshot_timestamp_seconds",
+            "Unix timestamp of the latest snapshot",
+            |v| v.done_at.map(|t| t as f64),
+        ),
+        (
+            "btm_replicated_height",
+            "Height of the last snapshot replicated to the target",
+            |v| v.replicated.map(|h| h as f64),
+        ),
+        (
+            "btm_stale",
+            "Whether snapshots are stale, see the `stale` config",
+            |v| v.stale.map(|s| s as u8 as f64),
+        ),
+    ];
+    for (name, help, get) in gauges {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for (vol, v) in reg.iter() {
+            if let Some(val) = get(v) {
+                let _ = writeln!(out, "{}{{volume=\"{}\"}} {}", name, escape(vol), val);
+            }
+        }
+    }
+
+    let name = "btm_operation_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {0} Duration of snapshot, prune, rollback and replicate operations\n# TYPE {0} histogram",
+        name
+    );
+    for (vol, v) in reg.iter() {
+        for (op, o) in v.ops.iter() {
+            let labels = format!("volume=\"{}\",op=\"{}\"", escape(vol), op);
+            let mut acc = 0;
+            for (b, n) in BUCKETS.iter().zip(o.buckets) {
+                acc += n;
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, b, acc);
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, o.count);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, o.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, o.count);
+        }
+    }
+
+    let name = "btm_operation_failures_total";
+    let _ = writeln!(
+        out,
+        "# HELP {0} Number of failed operations\n# TYPE {0} counter",
+        name
+    );
+    for (vol, v) in reg.iter() {
+        for (op, o) in v.ops.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{volume=\"{}\",op=\"{}\"}} {}",
+                name,
+                escape(vol),
+                op,
+                o.failures
+            );
+        }
+    }
+
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Serve metrics over HTTP in a background thread,
+/// an absolute path means a unix socket, others are TCP addresses;
+/// an existing socket file is replaced only if nobody is listening on it
+pub(crate) fn serve(addr: &str) -> Result<()> {
+    if Path::new(addr).is_absolute() {
+        // only a socket file left by a previous daemon can be replaced
+        match fs::symlink_metadata(addr) {
+            Ok(m) if !m.file_type().is_socket() => {
+                return Err(BtmError::InvalidConfig(format!(
+                    "{} exists and is not a socket",
+                    addr
+                )))
+            }
+            Ok(_) if UnixStream::connect(addr).is_ok() => {
+                return Err(BtmError::InvalidConfig(format!(
+                    "{} is in use by another process",
+                    addr
+                )))
+            }
+            Ok(_) => fs::remove_file(addr).c(d!())?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(eg!(e).into()),
+        }
+        let l = UnixListener::bind(addr).c(d!())?;
+        spawn(move || l.incoming().for_each(|c| served(c.c(d!()).and_then(reply))))
+    } else {
+        let l = TcpListener::bind(addr).c(d!())?;
+        spawn(move || l.incoming().for_each(|c| served(c.c(d!()).and_then(reply))))
+    }
+}
+
+fn served(res: ruc::Result<()>) {
+    if let Err(e) = res {
+        Event::new("metrics").debug(e.get_lowest_msg());
+    }
+}
+
+fn spawn(f: impl FnOnce() + Send + 'static) -> Result<()> {
+    thread::Builder::new()
+        .name("btm-metrics".to_owned())
+        .spawn(f)
+        .map(|_| ())
+        .c(d!())
+        .map_err(From::from)
+}
+
+// Clients are served one by one, scrapes are rare
+fn reply(c: impl Read + Write + Timeout) -> ruc::Result<()> {
+    c.set_timeouts(Duration::from_secs(5))?;
+    let mut c = BufReader::new(c);
+
+    // only the request line matters
+    let mut line = String::new();
+    c.read_line(&mut line).c(d!())?;
+    loop {
+        let mut header = String::new();
+        if 0 == c.read_line(&mut header).c(d!())? || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = if matches!(path, "/" | "/metrics") {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let c = c.get_mut();
+    write!(
+        c,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .c(d!())
+}
+
+trait Timeout {
+    fn set_timeouts(&self, t: Duration) -> ruc::Result<()>;
+}
+
+impl Timeout for std::net::TcpStream {
+    fn set_timeouts(&self, t: Duration) -> ruc::Result<()> {
+        self.set_read_timeout(Some(t)).c(d!())?;
+        self.set_write_timeout(Some(t)).c(d!())
+    }
+}
+
+impl Timeout for UnixStream {
+    fn set_timeouts(&self, t: Duration) -> ruc::Result<()> {
+        self.set_read_timeout(Some(t)).c(d!())?;
+        self.set_write_timeout(Some(t)).c(d!())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn replace_stale_sockets_only() {
+        let dir = env::temp_dir().join(format!("btm-test-{}-metrics", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("file");
+        fs::write(&file, "keep me").unwrap();
+        assert!(serve(file.to_str().unwrap()).is_err());
+        assert_eq!("keep me", fs::read_to_string(&file).unwrap());
+
+        // left by a previous daemon
+        let sock = dir.join("sock");
+        drop(UnixListener::bind(&sock).unwrap());
+        let addr = sock.to_str().unwrap();
+        serve(addr).unwrap();
+        assert!(matches!(serve(addr), Err(BtmError::InvalidConfig(_))));
+
+        let mut c = UnixStream::connect(&sock).unwrap();
+        c.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        c.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
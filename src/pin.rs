@@ -0,0 +1,27 @@
+//!
+//! # Pinned snapshots
+//!
+//! Pinned snapshots will never be cleaned up,
+//! neither by the `itv/cap` rules nor by a `btm clean`.
+//!
+
+use crate::{state, BtmCfg, Result};
+use std::collections::BTreeSet;
+
+pub(crate) fn load(cfg: &BtmCfg) -> Result<BTreeSet<u64>> {
+    state::load(&cfg.volume, "pins")
+}
+
+pub(crate) fn save(cfg: &BtmCfg, pins: &BTreeSet<u64>) -> Result<()> {
+    state::save(&cfg.volume, "pins", pins)
+}
+
+/// Filter out pinned ones
+pub(crate) fn unpinned(cfg: &BtmCfg, heights: &[u64]) -> Result<Vec<u64>> {
+    let pins = load(cfg)?;
+    Ok(heights
+        .iter()
+        .filter(|h| !pins.contains(h))
+        .copied()
+        .collect())
+}
@@ -0,0 +1,291 @@
+//!
+//! # Continuous replication
+//!
+//! `btm daemon` can replicate each new snapshot to a second pool for a hot-standby node,
+//! or pipe its stream to a command, eg. `ssh standby zfs recv -F tank/data`.
+//!
+//! Streams are incremental from the latest snapshot existing on both sides,
+//! the last replicated height is kept in the state directory;
+//! snapshots cleaned up on the source, or discarded by a rollback,
+//! are destroyed on the target as well.
+//!
+
+use crate::{
+    audit, driver,
+    logging::{Event, LogLevel},
+    metrics, state, BtmCfg, BtmError, Result, SnapMode,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    process::Command,
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+/// Where to replicate snapshots, disabled if both `target` and `pipe` are missing
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ReplicaCfg {
+    /// A dataset of another zfs pool, or a btrfs subvolume path on another filesystem,
+    /// received snapshots are named after it
+    pub target: Option<String>,
+    /// A shell command reading streams from its stdin, used if `target` is missing
+    pub pipe: Option<String>,
+    /// A shell command destroying snapshots received by `pipe`,
+    /// with `$BTM_HEIGHTS` set to the heights separated by spaces,
+    /// snapshots will pile up on the target if missing
+    pub pipe_destroy: Option<String>,
+}
+
+impl ReplicaCfg {
+    /// Whether replication is enabled
+    pub fn enabled(&self) -> bool {
+        self.target.is_some() || self.pipe.is_some()
+    }
+
+    pub(crate) fn check(&self, volume: &str) -> Result<()> {
+        if self.target.is_some() && self.pipe.is_some() {
+            return Err(BtmError::InvalidConfig(
+                "`replica.target` and `replica.pipe` can not be used together".to_owned(),
+            ));
+        }
+        if self.target.as_deref() == Some(volume) {
+            return Err(BtmError::InvalidConfig(
+                "`replica.target` can not be the volume itself".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Identity of the target, a change of it starts the replication over
+    fn dest(&self) -> String {
+        self.target
+            .clone()
+            .or_else(|| self.pipe.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Persistent state of the replication of a volume
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct State {
+    dest: String,
+    // the last replicated height
+    height: Option<u64>,
+    // heights that should exist on the target of a `pipe`,
+    // the target of a `target` is listed directly
+    heights: BTreeSet<u64>,
+}
+
+/// Replicates snapshots in a background thread, the latest one first,
+/// heights arriving during a replication are coalesced
+pub(crate) struct Replicator {
+    tx: Option<Sender<u64>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Replicator {
+    pub(crate) fn spawn(cfg: BtmCfg) -> Result<Self> {
+        cfg.check()?;
+        if let Ok(st) = load(&cfg) {
+            metrics::set_replicated(&cfg, st.height);
+        }
+
+        let (tx, rx) = mpsc::channel::<u64>();
+        let handle = thread::Builder::new()
+            .name("btm-replica".to_owned())
+            .spawn(move || {
+                while let Ok(idx) = rx.recv() {
+                    let latest = rx.try_iter().fold(idx, u64::max);
+                    run(&cfg, latest);
+                }
+            })
+            .c(d!())?;
+
+        Ok(Self {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Replicate the snapshot `idx` and all older ones needed
+    pub(crate) fn push(&self, idx: u64) {
+        if let Some(tx) = self.tx.as_ref() {
+            // the thread has exited if this fails, it has been logged
+            let _ = tx.send(idx);
+        }
+    }
+}
+
+impl Drop for Replicator {
+    // Wait for the replication in progress
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(h) = self.handle.take() {
+            if h.join().is_err() {
+                Event::new("replicate").error("the replicator panicked");
+            }
+        }
+    }
+}
+
+fn run(cfg: &BtmCfg, idx: u64) {
+    let start = Instant::now();
+    let ev = Event::new("replicate").volume(&cfg.volume).height(idx);
+    let mut rec = audit::start(cfg, "replicate", Some(idx));
+    let res = metrics::timed(cfg, "replicate", || replicate(cfg, idx));
+    match &res {
+        Ok(Some((base, destroyed))) => {
+            ev.since(start).info(format!(
+                "replicated to {}, {}",
+                cfg.replica.dest(),
+                base.map(|b| format!("incremental from {}", b))
+                    .unwrap_or_else(|| "full".to_owned())
+            ));
+            rec.destroyed = destroyed.clone();
+        }
+        // nothing has been done, not recorded
+        Ok(None) => return,
+        Err(e) => ev.since(start).fail(LogLevel::Error, e),
+    }
+    audit::finish(cfg, rec, &res);
+}
+
+// Return the base of the stream and the heights destroyed on the target,
+// `None` if `idx` has been replicated or cleaned up
+fn replicate(cfg: &BtmCfg, idx: u64) -> Result<Option<(Option<u64>, Vec<u64>)>> {
+    let rc = &cfg.replica;
+    let mut st = load(cfg)?;
+    if st.dest != rc.dest() {
+        st = State {
+            dest: rc.dest(),
+            ..Default::default()
+        };
+    }
+
+    let src = driver::sorted_snapshots(cfg)?;
+    if !src.contains(&idx) {
+        return Ok(None);
+    }
+    let target = target_cfg(cfg);
+    let on_target = match &target {
+        // btrfs snapshots are listed from the parent directory,
+        // the target subvolume itself is never created
+        Some(t) if SnapMode::Btrfs == t.mode || driver::exists(t)? => {
+            driver::sorted_snapshots(t)?.into_iter().collect()
+        }
+        Some(_) => BTreeSet::new(),
+        None => st.heights.clone(),
+    };
+    if on_target.contains(&idx) {
+        return Ok(None);
+    }
+
+    let base = on_target
+        .iter()
+        .rev()
+        .find(|h| **h < idx && src.contains(h))
+        .copied();
+    if base.is_none() && !on_target.is_empty() {
+        return Err(BtmError::InvalidConfig(format!(
+            "no snapshot in common with {}, clean it up to start over",
+            rc.dest()
+        )));
+    }
+
+    // discarded by a rollback of the source,
+    // an incremental stream can only be received on top of the latest snapshot
+    let newer = on_target
+        .range(base.map(|b| b + 1).unwrap_or_default()..)
+        .copied()
+        .collect::<Vec<_>>();
+    let mut destroyed = destroy(cfg, &newer)?;
+
+    let recv = match &target {
+        Some(t) => driver::recv_cmd(t, &cfg.volume, idx, true)?,
+        None => rc.pipe.clone().c(d!())?,
+    };
+    let cmd = format!(
+        "set -o pipefail; {} | {}",
+        driver::send_cmd(cfg, base, idx)?,
+        recv
+    );
+    driver::exec_output(&cmd)?;
+
+    st.height = Some(idx);
+    st.heights.insert(idx);
+    st.heights.retain(|h| !destroyed.contains(h));
+    save(cfg, &st)?;
+    metrics::set_replicated(cfg, st.height);
+
+    // the same retention as the source
+    let outdated = on_target
+        .iter()
+        .filter(|h| **h < idx && !src.contains(h) && !destroyed.contains(h))
+        .copied()
+        .collect::<Vec<_>>();
+    let pruned = destroy(cfg, &outdated)?;
+    st.heights.retain(|h| !pruned.contains(h));
+    save(cfg, &st)?;
+
+    destroyed.extend(pruned);
+    Ok(Some((base, destroyed)))
+}
+
+// Return the heights actually destroyed on the target
+fn destroy(cfg: &BtmCfg, heights: &[u64]) -> Result<Vec<u64>> {
+    if heights.is_empty() {
+        return Ok(vec![]);
+    }
+    if let Some(t) = target_cfg(cfg) {
+        return Ok(driver::destroy(&t, heights));
+    }
+
+    let cmd = match cfg.replica.pipe_destroy.as_deref() {
+        Some(c) => c,
+        None => return Ok(vec![]),
+    };
+    let list = heights
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let res = Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .env("BTM_VOLUME", &cfg.volume)
+        .env("BTM_HEIGHTS", &list)
+        .output()
+        .c(d!())?;
+    driver::check_output(cmd, res)?;
+    Ok(heights.to_vec())
+}
+
+// The target volume in the same mode, `None` for a `pipe`
+fn target_cfg(cfg: &BtmCfg) -> Option<BtmCfg> {
+    cfg.replica.target.as_ref().map(|t| BtmCfg {
+        volume: t.clone(),
+        mode: cfg.mode,
+        ..Default::default()
+    })
+}
+
+/// The last replicated height of the volume
+pub(crate) fn replicated(cfg: &BtmCfg) -> Option<u64> {
+    load(cfg)
+        .ok()
+        .filter(|st| st.dest == cfg.replica.dest())
+        .and_then(|st| st.height)
+}
+
+fn load(cfg: &BtmCfg) -> Result<State> {
+    state::load(&cfg.volume, "replica")
+}
+
+fn save(cfg: &BtmCfg, st: &State) -> Result<()> {
+    state::save(&cfg.volume, "replica", st)
+}
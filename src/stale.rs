@@ -0,0 +1,164 @@
+//!
+//! # Staleness of snapshots
+//!
+//! `btm daemon` is stale if no snapshot has been created for a while,
+//! or the latest snapshot falls too far behind the heights requested by the node,
+//! eg. the node has stopped sending heights, or snapshots keep failing.
+//!
+//! It is checked periodically, and exposed in `btm status` and the metrics;
+//! an alert command is executed each time it becomes stale.
+//!
+
+use crate::{
+    driver::{self, check_output},
+    logging::{Event, LogLevel},
+    metrics, BtmCfg,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    process::Command,
+    sync::Mutex,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Thresholds of staleness, each of them is disabled if missing
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct StaleCfg {
+    /// Stale if no snapshot has been created within this many seconds,
+    /// should cover the time of producing `itv` blocks
+    pub max_age: Option<u64>,
+    /// Stale if the latest snapshot is this many heights behind
+    /// the latest requested one, should be larger than `itv`
+    pub max_lag: Option<u64>,
+    /// A shell command to execute when the daemon becomes stale,
+    /// with `$BTM_VOLUME` and `$BTM_STALE_REASON` set
+    pub alert: Option<String>,
+}
+
+/// Tracks the requested and created snapshots of a daemon
+pub(crate) struct Tracker {
+    state: Mutex<State>,
+}
+
+struct State {
+    // unix timestamp of the start of the daemon
+    started: u64,
+    // unix timestamp of the latest snapshot
+    done_at: Option<u64>,
+    // height of the latest snapshot
+    done: Option<u64>,
+    // the highest height requested
+    requested: Option<u64>,
+    // why it is stale
+    reason: Option<String>,
+}
+
+impl Tracker {
+    /// `latest` is the latest existing snapshot
+    pub(crate) fn new(cfg: &BtmCfg, latest: Option<u64>) -> Self {
+        let done_at = latest.and_then(|h| driver::created_at(cfg, h).ok());
+        Self {
+            state: Mutex::new(State {
+                started: now(),
+                done_at,
+                done: latest,
+                requested: None,
+                reason: None,
+            }),
+        }
+    }
+
+    pub(crate) fn requested(&self, idx: u64) {
+        let mut st = self.state.lock().unwrap();
+        st.requested = st.requested.max(Some(idx));
+    }
+
+    pub(crate) fn done(&self, idx: u64) {
+        let mut st = self.state.lock().unwrap();
+        st.done = st.done.max(Some(idx));
+        st.done_at = Some(now());
+    }
+
+    /// The volume has been rolled back to `latest`,
+    /// heights requested before are meaningless now
+    pub(crate) fn rolled_back(&self, latest: Option<u64>) {
+        let mut st = self.state.lock().unwrap();
+        st.done = latest;
+        st.requested = None;
+    }
+
+    /// `(the highest requested height, why it is stale)`
+    pub(crate) fn get(&self) -> (Option<u64>, Option<String>) {
+        let st = self.state.lock().unwrap();
+        (st.requested, st.reason.clone())
+    }
+
+    /// Evaluate the thresholds of `cfg`, alert if it has just become stale
+    pub(crate) fn check(&self, cfg: &BtmCfg) {
+        let mut st = self.state.lock().unwrap();
+
+        let age = now().saturating_sub(st.done_at.unwrap_or(st.started));
+        let lag = st.requested.zip(st.done).map(|(r, d)| r.saturating_sub(d));
+        let reason = match (cfg.stale.max_age, cfg.stale.max_lag) {
+            (Some(max), _) if age > max => {
+                Some(format!("no snapshot within {}s, max_age is {}s", age, max))
+            }
+            (_, Some(max)) if lag.is_some_and(|l| l > max) => Some(format!(
+                "the latest snapshot is {} heights behind, max_lag is {}",
+                lag.unwrap_or_default(),
+                max
+            )),
+            _ => None,
+        };
+
+        metrics::set_staleness(cfg, reason.is_some(), st.done_at, st.requested);
+
+        let ev = Event::new("stale").volume(&cfg.volume).height(st.done);
+        match (&st.reason, &reason) {
+            // reported by `btm status` on its own, not as the last error
+            (None, Some(r)) => {
+                ev.warn(r);
+                if let Some(cmd) = cfg.stale.alert.clone() {
+                    alert(cmd, cfg.volume.clone(), r.clone());
+                }
+            }
+            (Some(_), None) => ev.info("recovered"),
+            _ => {}
+        }
+        st.reason = reason;
+    }
+}
+
+// The daemon should not wait for the alert command
+fn alert(cmd: String, volume: String, reason: String) {
+    let res = thread::Builder::new()
+        .name("btm-alert".to_owned())
+        .spawn(move || {
+            let res = Command::new("bash")
+                .arg("-c")
+                .arg(&cmd)
+                .env("BTM_VOLUME", &volume)
+                .env("BTM_STALE_REASON", &reason)
+                .output()
+                .c(d!())
+                .map_err(From::from)
+                .and_then(|o| check_output(&cmd, o));
+            if let Err(e) = res {
+                Event::new("alert").volume(&volume).fail(LogLevel::Warn, &e);
+            }
+        });
+    if let Err(e) = res {
+        Event::new("alert").warn(e);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
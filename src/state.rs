@@ -0,0 +1,53 @@
+//!
+//! # Persistent states of btm
+//!
+//! Each volume has its own files in the state directory,
+//! eg. `/var/lib/btm/zroot%data.pins` for the pin list of `zroot/data`.
+//!
+//! A state file is replaced as a whole,
+//! so it will never be half-written, even if the host crashes.
+//!
+
+use crate::Result;
+use ruc::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// Persistent states of btm are placed in this directory
+pub(crate) const STATE_DIR: &str = "/var/lib/btm";
+
+/// The state file of `volume` with the extension `ext`
+#[inline(always)]
+pub(crate) fn path(volume: &str, ext: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(format!("{}.{}", volume.replace('/', "%"), ext))
+}
+
+/// The default value is returned if the file does not exist
+pub(crate) fn load<T: DeserializeOwned + Default>(volume: &str, ext: &str) -> Result<T> {
+    match fs::read(path(volume, ext)) {
+        Ok(b) => serde_json::from_slice(&b).c(d!()).map_err(|e| e.into()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(eg!(e).into()),
+    }
+}
+
+// Write to a temporary file at first, sync it to disk, and then rename it,
+// the directory is synced at last, so that the rename itself is durable
+pub(crate) fn save<T: Serialize>(volume: &str, ext: &str, v: &T) -> Result<()> {
+    fs::create_dir_all(STATE_DIR).c(d!())?;
+    let path = path(volume, ext);
+    let tmp = path.with_extension(format!("{}.tmp", ext));
+
+    let mut f = File::create(&tmp).c(d!())?;
+    f.write_all(&serde_json::to_vec(v).c(d!())?).c(d!())?;
+    f.sync_all().c(d!())?;
+    fs::rename(&tmp, &path).c(d!())?;
+    File::open(Path::new(STATE_DIR))
+        .and_then(|d| d.sync_all())
+        .c(d!())
+        .map_err(|e| e.into())
+}
@@ -0,0 +1,483 @@
+//!
+//! # State-sync snapshots
+//!
+//! For peers bootstrapping by state sync, eg. on Cosmos-style chains,
+//! a snapshot is served as fixed-size chunks of its archive, see [crate::ArchiveManifest].
+//!
+//! Chunks are content-addressed, each of them is stored once as `chunks/<sha256>` in a store,
+//! and `<height>.json` lists the hashes of the chunks of a snapshot, see [SyncSnapshot];
+//! they are verified while being served and applied,
+//! and then the archive verifies every file before moving it into the volume.
+//!
+//! The snapshot itself comes from the peers, so a restore starts only if
+//! its hash matches the trusted one, got from outside, eg. from the light client.
+//!
+
+use crate::{
+    archive, export, lock::VolumeLock, logging::Event, ArchiveManifest, BtmCfg, BtmError, Result,
+    SnapMode,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the format of state-sync snapshots
+pub const SYNC_FORMAT: u32 = 1;
+
+/// 10MiB, the same as most Cosmos-style chains
+pub const DEFAULT_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+const CHUNKS: &str = "chunks";
+
+/// A state-sync snapshot, the offer to peers
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SyncSnapshot {
+    /// Height of the snapshot
+    pub height: u64,
+    /// Version of the format
+    pub format: u32,
+    /// Hex SHA-256 digest of the concatenated hashes of all chunks,
+    /// it identifies the snapshot
+    pub hash: String,
+    /// Hex SHA-256 digest of each chunk, in order
+    pub chunks: Vec<String>,
+    /// Size of each chunk in bytes, except the last one
+    pub chunk_size: u64,
+    /// Size of all chunks in bytes
+    pub size: u64,
+    /// The volume it is generated from
+    pub volume: String,
+    /// Mode of the volume, it does not restrict the restore
+    pub mode: SnapMode,
+    /// Unix timestamp of the creation of the snapshot, if known
+    pub created: Option<u64>,
+    /// Unix timestamp of the generation
+    pub ts: u64,
+}
+
+impl SyncSnapshot {
+    /// Check `chunk` against the hash of the chunk `index`
+    pub fn verify_chunk(&self, index: u32, chunk: &[u8]) -> Result<()> {
+        match self.chunks.get(index as usize) {
+            Some(h) if *h == digest(chunk) => Ok(()),
+            Some(_) => Err(BtmError::InvalidChunk(index)),
+            None => Err(eg!(
+                "chunk {} is out of range, {} chunks in total",
+                index,
+                self.chunks.len()
+            )
+            .into()),
+        }
+    }
+
+    // The format is supported, and the hash matches the chunks
+    fn check(&self) -> Result<()> {
+        if self.format != SYNC_FORMAT {
+            return Err(BtmError::InvalidStore(format!(
+                "unsupported format of the snapshot {}: {}",
+                self.height, self.format
+            )));
+        }
+        if self.hash != list_hash(&self.chunks) {
+            return Err(BtmError::InvalidStore(format!(
+                "the hash of the snapshot {} does not match its chunks",
+                self.height
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A directory holding state-sync snapshots, see the module doc
+#[derive(Clone, Debug)]
+pub struct SyncStore {
+    dir: PathBuf,
+}
+
+impl SyncStore {
+    /// Use `dir` as a store, it is created on the first snapshot
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// All snapshots in the store, the latest one first
+    pub fn list(&self) -> Result<Vec<SyncSnapshot>> {
+        let mut res = vec![];
+        for h in self.heights()? {
+            res.push(self.get(h)?);
+        }
+        Ok(res)
+    }
+
+    /// The snapshot of `height`
+    pub fn get(&self, height: u64) -> Result<SyncSnapshot> {
+        let path = self.dir.join(format!("{}.json", height));
+        let b = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(BtmError::HeightNotFound(height))
+            }
+            Err(e) => return Err(eg!(e).into()),
+        };
+        let s: SyncSnapshot = serde_json::from_slice(&b).map_err(|e| self.invalid(e))?;
+        s.check().map(|_| s)
+    }
+
+    /// Load the chunk `index` of the snapshot `height`, verified against its hash
+    pub fn load_chunk(&self, height: u64, index: u32) -> Result<Vec<u8>> {
+        let s = self.get(height)?;
+        let h = s.chunks.get(index as usize).ok_or_else(|| {
+            self.invalid(format!("no chunk {} in the snapshot {}", index, height))
+        })?;
+        let chunk = fs::read(self.dir.join(CHUNKS).join(h)).map_err(|e| self.invalid(e))?;
+        s.verify_chunk(index, &chunk)
+            .map_err(|_| self.invalid(format!("chunk {} is broken", h)))?;
+        Ok(chunk)
+    }
+
+    /// Keep the latest `keep` snapshots, and remove chunks no longer referenced,
+    /// return the heights removed
+    pub fn prune(&self, keep: usize) -> Result<Vec<u64>> {
+        let _lk = export::lock(&self.dir, "pruning")?;
+
+        let heights = self.heights()?;
+        let (kept, removed) = heights.split_at(keep.min(heights.len()));
+        for h in removed {
+            fs::remove_file(self.dir.join(format!("{}.json", h))).c(d!())?;
+        }
+
+        let mut used = BTreeSet::new();
+        for h in kept {
+            used.extend(self.get(*h)?.chunks);
+        }
+        for e in fs::read_dir(self.dir.join(CHUNKS)).c(d!())? {
+            let e = e.c(d!())?;
+            if !used.contains(e.file_name().to_string_lossy().as_ref()) {
+                fs::remove_file(e.path()).c(d!())?;
+            }
+        }
+        Ok(removed.to_vec())
+    }
+
+    // Heights of all snapshots, in 'DESC' order
+    fn heights(&self) -> Result<Vec<u64>> {
+        let mut res = match fs::read_dir(&self.dir) {
+            Ok(rd) => rd
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    e.file_name()
+                        .to_str()?
+                        .strip_suffix(".json")?
+                        .parse::<u64>()
+                        .ok()
+                })
+                .collect::<Vec<_>>(),
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(eg!(e).into()),
+        };
+        res.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(res)
+    }
+
+    fn invalid(&self, msg: impl ToString) -> BtmError {
+        BtmError::InvalidStore(format!("{}: {}", self.dir.display(), msg.to_string()))
+    }
+}
+
+/// Generate the state-sync snapshot of `idx` into `store`
+pub(crate) fn create(
+    cfg: &BtmCfg,
+    store: &SyncStore,
+    idx: u64,
+    chunk_size: u64,
+) -> Result<SyncSnapshot> {
+    if 0 == chunk_size {
+        return Err(BtmError::InvalidConfig(
+            "`chunk_size` must be larger than 0".to_owned(),
+        ));
+    }
+    let start = Instant::now();
+    fs::create_dir_all(store.dir.join(CHUNKS)).c(d!())?;
+    let _lk = export::lock(&store.dir, "a state-sync snapshot into")?;
+
+    let chunker = Chunker::new(store.dir.join(CHUNKS), chunk_size as usize);
+    let (m, chunker) = archive::write(cfg, idx, chunker)?;
+    let (chunks, size) = chunker.finish().c(d!())?;
+
+    let s = SyncSnapshot {
+        height: idx,
+        format: SYNC_FORMAT,
+        hash: list_hash(&chunks),
+        chunks,
+        chunk_size,
+        size,
+        volume: cfg.volume.clone(),
+        mode: cfg.mode,
+        created: m.created,
+        ts: now(),
+    };
+
+    // write to a temporary file at first, and then rename it
+    let path = store.dir.join(format!("{}.json", idx));
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&s).c(d!())?).c(d!())?;
+    fs::rename(tmp, path).c(d!())?;
+
+    Event::new("sync-snapshot")
+        .volume(&cfg.volume)
+        .height(idx)
+        .since(start)
+        .info(format!(
+            "{} chunks, {} bytes in {}",
+            s.chunks.len(),
+            s.size,
+            store.dir.display()
+        ));
+    Ok(s)
+}
+
+/// Restore a state-sync snapshot into a new volume,
+/// chunks are applied in order as they arrive from peers, and unpacked in the background.
+///
+/// ```ignore
+/// let mut r = cfg.sync_restore(snapshot, &trusted_hash)?;
+/// while let Some(i) = r.next() {
+///     r.apply(i, &fetch_chunk(i)?)?;
+/// }
+/// r.finish()?;
+/// ```
+///
+/// It is aborted if dropped before [SyncRestore::finish],
+/// nothing is left in the volume then.
+pub struct SyncRestore {
+    snapshot: SyncSnapshot,
+    next: u32,
+    tx: Option<SyncSender<Vec<u8>>>,
+    handle: Option<JoinHandle<Result<ArchiveManifest>>>,
+    _lk: VolumeLock,
+}
+
+impl SyncRestore {
+    pub(crate) fn start(
+        cfg: &BtmCfg,
+        snapshot: SyncSnapshot,
+        hash: &str,
+        lk: VolumeLock,
+    ) -> Result<Self> {
+        snapshot.check()?;
+        // chunks are only verified against the snapshot itself,
+        // which comes from the peers as well
+        if snapshot.hash != hash {
+            return Err(BtmError::InvalidStore(format!(
+                "the hash of the snapshot {} is {}, not the trusted {}",
+                snapshot.height, snapshot.hash, hash
+            )));
+        }
+
+        // at most one chunk is waiting, so that the memory usage is bounded
+        let (tx, rx) = mpsc::sync_channel(1);
+        let mut r = ChunkReader {
+            rx,
+            buf: vec![],
+            pos: 0,
+            left: snapshot.size,
+        };
+        let cfg = cfg.clone();
+        let src = format!("state-sync snapshot {}", snapshot.height);
+        let handle = thread::Builder::new()
+            .name("btm-sync".to_owned())
+            .spawn(move || {
+                let res = archive::restore_from(&cfg, "sync-restore", &src, &mut r);
+                // the padding at the end of the archive may be left
+                if res.is_ok() {
+                    while r.rx.recv().is_ok() {}
+                }
+                res
+            })
+            .c(d!())?;
+
+        Ok(Self {
+            snapshot,
+            next: 0,
+            tx: Some(tx),
+            handle: Some(handle),
+            _lk: lk,
+        })
+    }
+
+    /// The snapshot being restored
+    pub fn snapshot(&self) -> &SyncSnapshot {
+        &self.snapshot
+    }
+
+    /// The index of the chunk to apply next, `None` if all chunks have been applied
+    pub fn next(&self) -> Option<u32> {
+        alt!(
+            (self.next as usize) < self.snapshot.chunks.len(),
+            Some(self.next),
+            None
+        )
+    }
+
+    /// Verify and apply the chunk `index`, chunks must be applied in order;
+    /// [BtmError::InvalidChunk] means it should be fetched again, maybe from another peer
+    pub fn apply(&mut self, index: u32, chunk: &[u8]) -> Result<()> {
+        match self.next() {
+            Some(n) if n == index => {}
+            Some(n) => {
+                return Err(eg!("chunk {} is applied out of order, expecting {}", index, n).into())
+            }
+            None => return Err(eg!("all chunks have been applied").into()),
+        }
+        self.snapshot.verify_chunk(index, chunk)?;
+
+        let tx = self.tx.as_ref().c(d!("the restore has failed"))?;
+        if tx.send(chunk.to_vec()).is_err() {
+            // the unpacking has failed
+            self.tx.take();
+            return Err(self.join().err().unwrap_or_else(|| eg!().into()));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Wait for the unpacking, and take a snapshot of the restored volume
+    pub fn finish(mut self) -> Result<ArchiveManifest> {
+        let missing = self.snapshot.chunks.len() - self.next as usize;
+        self.tx.take();
+        let res = self.join();
+        if 0 < missing {
+            return Err(eg!("{} chunks have not been applied", missing).into());
+        }
+        res
+    }
+
+    fn join(&mut self) -> Result<ArchiveManifest> {
+        let h = self.handle.take().c(d!("the restore has failed"))?;
+        h.join()
+            .map_err(|_| eg!("the restore thread panicked"))
+            .and_then(|res| res.map_err(From::from))
+            .map_err(From::from)
+    }
+}
+
+impl Drop for SyncRestore {
+    // The unpacking fails on the missing chunks, and cleans up after itself
+    fn drop(&mut self) {
+        self.tx.take();
+        let _ = self.join();
+    }
+}
+
+// Cut the stream into chunks, and store each of them once
+struct Chunker {
+    dir: PathBuf,
+    size: usize,
+    buf: Vec<u8>,
+    chunks: Vec<String>,
+    total: u64,
+}
+
+impl Chunker {
+    fn new(dir: PathBuf, size: usize) -> Self {
+        Self {
+            dir,
+            size,
+            buf: Vec::with_capacity(size),
+            chunks: vec![],
+            total: 0,
+        }
+    }
+
+    fn cut(&mut self) -> io::Result<()> {
+        let h = digest(&self.buf);
+        let path = self.dir.join(&h);
+        if !path.exists() {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &self.buf)?;
+            fs::rename(tmp, path)?;
+        }
+        self.chunks.push(h);
+        self.total += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    // Hashes of all chunks, and the total size
+    fn finish(mut self) -> io::Result<(Vec<String>, u64)> {
+        if !self.buf.is_empty() {
+            self.cut()?;
+        }
+        Ok((self.chunks, self.total))
+    }
+}
+
+impl Write for Chunker {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.size {
+            self.cut()?;
+        }
+        Ok(n)
+    }
+
+    // chunks are only cut at the fixed size
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Read the applied chunks in order
+struct ChunkReader {
+    rx: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+    // bytes not read yet
+    left: u64,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if 0 == self.left {
+            return Ok(0);
+        }
+        while self.pos == self.buf.len() {
+            self.buf = self.rx.recv().map_err(|_| {
+                io::Error::new(ErrorKind::UnexpectedEof, "the restore has been aborted")
+            })?;
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        self.left = self.left.saturating_sub(n as u64);
+        Ok(n)
+    }
+}
+
+fn digest(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn list_hash(chunks: &[String]) -> String {
+    digest(chunks.concat().as_bytes())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
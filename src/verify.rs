@@ -0,0 +1,266 @@
+//!
+//! # Integrity verification of snapshots
+//!
+//! `btm verify` checks a snapshot before it is trusted, eg. before a rollback:
+//!
+//! - `read`, every file of the snapshot can be read through,
+//!   so that zfs and btrfs verify the checksums of all its blocks
+//! - `pool`, the pool holding it has no known data errors,
+//!   `zpool status` in the zfs mode, `btrfs device stats` in the btrfs mode
+//! - `digest`, its contents still match the digest recorded when it was created,
+//!   see [VerifyCfg::digest]
+//! - `validate`, a user-provided command accepts its contents,
+//!   see [VerifyCfg::validate]
+//!
+//! Nothing can be changed by the checks, the command of `validate` is refused
+//! if the snapshot is writable, eg. a btrfs one created by an older version.
+//!
+
+use crate::{
+    archive, driver,
+    index::{snapshots, SnapIndex},
+    logging::{Event, LogLevel},
+    state, BtmCfg, BtmError, Result,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::Command,
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+/// How snapshots are verified
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct VerifyCfg {
+    /// Record the content digest of each snapshot created by a [SnapWorker](crate::SnapWorker),
+    /// eg. `btm daemon`; it reads the whole snapshot in a background thread,
+    /// disabled by default
+    pub digest: bool,
+    /// A shell command validating the contents of a snapshot,
+    /// eg. a consistency check of the database of the node,
+    /// executed in the snapshot directory with
+    /// `$BTM_VOLUME`, `$BTM_HEIGHT` and `$BTM_SNAPSHOT_PATH` set,
+    /// the check fails without running it if the snapshot is not read-only
+    pub validate: Option<String>,
+}
+
+/// Result of a check
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    /// Passed
+    Ok,
+    /// Failed, the snapshot should not be trusted
+    Failed,
+    /// Not performed, eg. no digest has been recorded
+    Skipped,
+}
+
+/// A check of [VerifyReport]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Check {
+    /// `read`, `pool`, `digest` or `validate`, see the module doc
+    pub name: String,
+    /// Result of the check
+    pub status: CheckStatus,
+    /// Details, eg. the reason of a failure
+    pub msg: String,
+}
+
+/// Results of `btm verify`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerifyReport {
+    /// The verified volume
+    pub volume: String,
+    /// Height of the snapshot
+    pub height: u64,
+    /// All checks, in the order of execution
+    pub checks: Vec<Check>,
+}
+
+impl VerifyReport {
+    /// Whether no check has failed
+    pub fn ok(&self) -> bool {
+        self.checks.iter().all(|c| CheckStatus::Failed != c.status)
+    }
+
+    fn push(&mut self, name: &str, status: CheckStatus, msg: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.to_owned(),
+            status,
+            msg: msg.into(),
+        });
+    }
+}
+
+/// Verify the snapshot `idx`, the latest one by default, see the module doc;
+/// failed checks are reported instead of being returned as errors
+pub(crate) fn verify(cfg: &BtmCfg, idx: Option<u64>) -> Result<VerifyReport> {
+    let snaps = driver::sorted_snapshots(cfg)?;
+    let idx = match idx {
+        Some(h) if !snaps.contains(&h) => return Err(BtmError::HeightNotFound(h)),
+        Some(h) => h,
+        None => snaps.first().copied().ok_or(BtmError::NoSnapshots)?,
+    };
+
+    let start = Instant::now();
+    let mut report = VerifyReport {
+        volume: cfg.volume.clone(),
+        height: idx,
+        checks: vec![],
+    };
+
+    let path = driver::snapshot_path(cfg, idx)?;
+    let digest = match archive::digest(&path) {
+        Ok((d, n)) => {
+            report.push("read", CheckStatus::Ok, format!("{} files", n));
+            Some(d)
+        }
+        Err(e) => {
+            report.push("read", CheckStatus::Failed, e.to_string());
+            None
+        }
+    };
+
+    match driver::pool_status(cfg, idx) {
+        Ok((true, msg)) => report.push("pool", CheckStatus::Ok, msg),
+        Ok((false, msg)) => report.push("pool", CheckStatus::Failed, msg),
+        Err(e) => report.push("pool", CheckStatus::Failed, e.to_string()),
+    }
+
+    match (load(cfg)?.get(&idx), digest) {
+        (None, _) => report.push("digest", CheckStatus::Skipped, "not recorded"),
+        (Some(_), None) => report.push("digest", CheckStatus::Skipped, "unreadable"),
+        (Some(r), Some(d)) if *r == d => report.push("digest", CheckStatus::Ok, d),
+        (Some(r), Some(d)) => report.push(
+            "digest",
+            CheckStatus::Failed,
+            format!("{} recorded, {} found", r, d),
+        ),
+    }
+
+    match cfg.verify.validate.as_deref() {
+        None => report.push("validate", CheckStatus::Skipped, "no command"),
+        Some(cmd) => match validate(cfg, idx, &path, cmd) {
+            Ok(out) => report.push("validate", CheckStatus::Ok, out.trim()),
+            Err(e) => report.push("validate", CheckStatus::Failed, e.to_string()),
+        },
+    }
+
+    let ev = Event::new("verify")
+        .volume(&cfg.volume)
+        .height(idx)
+        .since(start);
+    let failed = report
+        .checks
+        .iter()
+        .filter(|c| CheckStatus::Failed == c.status)
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>();
+    if failed.is_empty() {
+        ev.info("snapshot verified");
+    } else {
+        ev.warn(format!("checks failed: {}", failed.join(", ")));
+    }
+
+    Ok(report)
+}
+
+fn validate(cfg: &BtmCfg, idx: u64, path: &Path, cmd: &str) -> Result<String> {
+    if !driver::read_only(cfg, idx)? {
+        return Err(eg!(
+            "{} is writable, the command is not executed",
+            path.display()
+        )
+        .into());
+    }
+    let res = Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .current_dir(path)
+        .env("BTM_VOLUME", &cfg.volume)
+        .env("BTM_HEIGHT", idx.to_string())
+        .env("BTM_SNAPSHOT_PATH", path)
+        .output()
+        .c(d!())?;
+    driver::check_output(cmd, res)
+}
+
+/// Records content digests of new snapshots in a background thread,
+/// so that reading a whole snapshot never delays the next one;
+/// heights arriving during a recording are coalesced into the latest one
+pub(crate) struct Recorder {
+    tx: Sender<(BtmCfg, u64)>,
+}
+
+impl Recorder {
+    /// The thread exits once this is dropped,
+    /// a recording in progress is not waited for, it can take minutes
+    pub(crate) fn spawn(index: Option<Arc<SnapIndex>>) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<(BtmCfg, u64)>();
+        thread::Builder::new()
+            .name("btm-digest".to_owned())
+            .spawn(move || {
+                while let Ok(first) = rx.recv() {
+                    let (cfg, idx) = rx.try_iter().fold(first, |a, b| alt!(a.1 < b.1, b, a));
+                    record(&cfg, index.as_deref(), idx);
+                }
+            })
+            .c(d!())?;
+        Ok(Self { tx })
+    }
+
+    pub(crate) fn push(&self, cfg: BtmCfg, idx: u64) {
+        // the thread has exited if this fails, it has been logged
+        let _ = self.tx.send((cfg, idx));
+    }
+}
+
+/// Record the content digest of the snapshot `idx`,
+/// digests of destroyed snapshots are dropped at the same time;
+/// failures are logged, the snapshot itself has been created
+fn record(cfg: &BtmCfg, index: Option<&SnapIndex>, idx: u64) {
+    let start = Instant::now();
+    let ev = Event::new("digest").volume(&cfg.volume).height(idx);
+    match read(cfg, index, idx) {
+        Ok(n) => ev
+            .since(start)
+            .debug(format!("digest of {} files recorded", n)),
+        Err(e) => ev.since(start).fail(LogLevel::Warn, &e),
+    }
+}
+
+// The volume is not locked while reading, or snapshots could not be created meanwhile;
+// the result is saved under the lock, and only if the snapshot is still the one read,
+// it may have been destroyed, eg. by a rollback, and created again
+fn read(cfg: &BtmCfg, index: Option<&SnapIndex>, idx: u64) -> Result<usize> {
+    let created = driver::created_at(cfg, idx).ok();
+    let (d, n) = archive::digest(&driver::snapshot_path(cfg, idx)?)?;
+
+    let _lk = cfg.lock(&format!("digest {}", idx))?;
+    let snaps = snapshots(cfg, index)?;
+    if !snaps.contains(&idx) || driver::created_at(cfg, idx).ok() != created {
+        return Err(eg!("the snapshot has been destroyed while being read").into());
+    }
+    let mut digests = load(cfg)?;
+    digests.retain(|h, _| snaps.contains(h));
+    digests.insert(idx, d);
+    save(cfg, &digests).map(|_| n)
+}
+
+fn load(cfg: &BtmCfg) -> Result<BTreeMap<u64, String>> {
+    state::load(&cfg.volume, "digests")
+}
+
+fn save(cfg: &BtmCfg, digests: &BTreeMap<u64, String>) -> Result<()> {
+    state::save(&cfg.volume, "digests", digests)
+}
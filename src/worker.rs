@@ -0,0 +1,501 @@
+//!
+//! # Background snapshot pipeline
+//!
+//! Heights are sent to a dedicated thread over a channel,
+//! so that the caller never waits for listing, pruning or the zfs/btrfs commands.
+//!
+//! NOTE:
+//! a snapshot always captures the state at the moment it is created,
+//! so backlogged heights are coalesced into the latest one of them,
+//! and heights lower than a processed one are refused,
+//! until the volume is rolled back, see [SnapWorker::rolled_back].
+//!
+
+use crate::{
+    audit, driver,
+    index::{snapshots, SnapIndex},
+    logging::{Event, LogLevel},
+    metrics, verify, BtmCfg, BtmError, Result, CAP_MAX,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ops::Bound::{Excluded, Unbounded},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, MutexGuard, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How many statuses will be kept for `status`/`wait` queries
+const STATUS_HISTORY: usize = CAP_MAX as usize;
+
+/// State of a height submitted to a [SnapWorker]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SnapStatus {
+    /// Waiting in the queue
+    Pending,
+    /// The snapshot has been created
+    Done,
+    /// No snapshot is needed, filtered by `itv`
+    Skipped,
+    /// Coalesced into this later height, whose snapshot captures the state instead
+    Coalesced(u64),
+    /// The snapshot had been created before, eg. by an earlier run
+    Exists,
+    /// Failed to create the snapshot
+    Failed(String),
+}
+
+/// Called once a height has been processed
+pub(crate) type Notify = Box<dyn Fn(u64, &SnapStatus) + Send + Sync>;
+
+#[derive(Default)]
+struct Shared {
+    // can be replaced while running
+    cfg: RwLock<BtmCfg>,
+    status: Mutex<BTreeMap<u64, SnapStatus>>,
+    cond: Condvar,
+    // the height of the last snapshot, reset by a rollback
+    last: Mutex<Option<u64>>,
+    // held while creating a snapshot, see `SnapWorker::hold`
+    gate: Mutex<()>,
+    notify: Option<Notify>,
+    // set by `SnapWorker::shutdown`
+    stopping: AtomicBool,
+}
+
+impl Shared {
+    fn set(&self, idx: u64, st: SnapStatus) {
+        let mut status = self.status.lock().unwrap();
+        status.insert(idx, st.clone());
+        evict(&mut status);
+        self.update_queue(&status);
+        drop(status);
+        self.cond.notify_all();
+
+        if let (Some(f), false) = (self.notify.as_ref(), matches!(st, SnapStatus::Pending)) {
+            f(idx, &st);
+        }
+    }
+
+    fn pending(&self, idx: u64) -> bool {
+        matches!(
+            self.status.lock().unwrap().get(&idx),
+            Some(SnapStatus::Pending)
+        )
+    }
+
+    fn remove(&self, idx: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.remove(&idx);
+        self.update_queue(&status);
+        drop(status);
+        self.cond.notify_all();
+    }
+
+    fn update_queue(&self, status: &BTreeMap<u64, SnapStatus>) {
+        let n = status
+            .values()
+            .filter(|s| matches!(s, SnapStatus::Pending))
+            .count();
+        metrics::set_queue(&self.cfg.read().unwrap(), n);
+    }
+}
+
+// Drop the oldest processed heights beyond `STATUS_HISTORY`,
+// queued ones are kept, or `wait` on them would return `None`
+fn evict(status: &mut BTreeMap<u64, SnapStatus>) {
+    while STATUS_HISTORY < status.len() {
+        let oldest = status
+            .iter()
+            .find(|(_, s)| !matches!(s, SnapStatus::Pending))
+            .map(|(h, _)| *h);
+        match oldest {
+            Some(h) => status.remove(&h),
+            None => break,
+        };
+    }
+}
+
+/// A handle of the background snapshot thread,
+/// created by [BtmCfg::spawn_worker](crate::BtmCfg::spawn_worker).
+///
+/// Dropping it will wait for all queued heights to be processed,
+/// use [SnapWorker::shutdown] to exit sooner.
+pub struct SnapWorker {
+    tx: Option<SyncSender<u64>>,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SnapWorker {
+    pub(crate) fn spawn(
+        cfg: BtmCfg,
+        notify: Option<Notify>,
+        index: Option<Arc<SnapIndex>>,
+    ) -> Result<Self> {
+        cfg.refuse_external("spawn_worker")?;
+
+        let (tx, rx) = mpsc::sync_channel(cfg.queue_size);
+        let shared = Arc::new(Shared {
+            cfg: RwLock::new(cfg),
+            notify,
+            ..Default::default()
+        });
+
+        let digests = verify::Recorder::spawn(index.clone())?;
+        let s = Arc::clone(&shared);
+        let handle = thread::Builder::new()
+            .name("btm-worker".to_owned())
+            .spawn(move || work(rx, s, index, digests))
+            .c(d!())?;
+
+        Ok(Self {
+            tx: Some(tx),
+            shared,
+            handle: Some(handle),
+        })
+    }
+
+    /// Queue a height, return immediately,
+    /// a height will not be queued again unless it has failed.
+    ///
+    /// `BtmError::QueueFull` is returned if there are
+    /// already `queue_size` heights in the queue.
+    pub fn submit(&self, idx: u64) -> Result<()> {
+        if self
+            .status(idx)
+            .is_some_and(|s| !matches!(s, SnapStatus::Failed(_)))
+        {
+            return Ok(());
+        }
+
+        if !driver::itv_matched(&self.shared.cfg.read().unwrap(), idx) {
+            self.shared.set(idx, SnapStatus::Skipped);
+            return Ok(());
+        }
+
+        self.shared.set(idx, SnapStatus::Pending);
+        match self.tx.as_ref().c(d!())?.try_send(idx) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.shared.remove(idx);
+                Err(BtmError::QueueFull)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.shared.remove(idx);
+                Err(eg!("the worker has exited").into())
+            }
+        }
+    }
+
+    /// Apply a new config to later heights,
+    /// the `volume`, `mode` and `queue_size` should not be changed
+    pub(crate) fn set_cfg(&self, cfg: BtmCfg) {
+        *self.shared.cfg.write().unwrap() = cfg;
+    }
+
+    /// Get the current status of a submitted height,
+    /// `None` if it is unknown or too old
+    pub fn status(&self, idx: u64) -> Option<SnapStatus> {
+        self.shared.status.lock().unwrap().get(&idx).cloned()
+    }
+
+    /// Wait until the submitted height has been processed,
+    /// `None` if it is unknown or too old
+    pub fn wait(&self, idx: u64) -> Option<SnapStatus> {
+        let status = self.shared.status.lock().unwrap();
+        let status = self
+            .shared
+            .cond
+            .wait_while(status, |s| matches!(s.get(&idx), Some(SnapStatus::Pending)))
+            .unwrap();
+        status.get(&idx).cloned()
+    }
+
+    /// Like `wait`, but return the `Pending` status after a `timeout`
+    pub fn wait_timeout(&self, idx: u64, timeout: Duration) -> Option<SnapStatus> {
+        let status = self.shared.status.lock().unwrap();
+        let (status, _) = self
+            .shared
+            .cond
+            .wait_timeout_while(status, timeout, |s| {
+                matches!(s.get(&idx), Some(SnapStatus::Pending))
+            })
+            .unwrap();
+        status.get(&idx).cloned()
+    }
+
+    /// Forget heights later than `target` once the volume has been rolled back to it,
+    /// so that they can be submitted and created again;
+    /// the queued ones are discarded and marked as `Failed`
+    pub fn rolled_back(&self, target: u64) {
+        *self.shared.last.lock().unwrap() = None;
+
+        let mut status = self.shared.status.lock().unwrap();
+        let later = status
+            .range((Excluded(target), Unbounded))
+            .map(|(h, s)| (*h, matches!(s, SnapStatus::Pending)))
+            .collect::<Vec<_>>();
+        let mut queued = vec![];
+        for (h, pending) in later {
+            alt!(pending, queued.push(h), {
+                status.remove(&h);
+            });
+        }
+        drop(status);
+
+        let msg = format!("discarded by a rollback to {}", target);
+        queued.into_iter().for_each(|h| {
+            Event::new("snapshot").height(h).warn(&msg);
+            self.shared.set(h, SnapStatus::Failed(msg.clone()))
+        });
+    }
+
+    /// No snapshot will be created until the guard is dropped,
+    /// so that a rollback can not race with the snapshot of a stale height
+    pub(crate) fn hold(&self) -> MutexGuard<'_, ()> {
+        self.shared.gate.lock().unwrap()
+    }
+
+    /// Wait for the snapshot in progress, if any, and then stop the worker,
+    /// heights still in the queue are marked as `Failed`
+    pub fn shutdown(self) {
+        self.shared.stopping.store(true, Ordering::Relaxed);
+        // the rest is done by `drop`
+    }
+}
+
+impl Drop for SnapWorker {
+    fn drop(&mut self) {
+        // close the channel, and then the worker will exit
+        self.tx.take();
+        if let Some(h) = self.handle.take() {
+            if h.join().is_err() {
+                Event::new("worker").error("the worker panicked");
+            }
+        }
+    }
+}
+
+fn work(
+    rx: Receiver<u64>,
+    shared: Arc<Shared>,
+    index: Option<Arc<SnapIndex>>,
+    digests: verify::Recorder,
+) {
+    let index = index.as_deref();
+
+    while let Ok(idx) = rx.recv() {
+        let mut batch = vec![idx];
+        batch.extend(rx.try_iter());
+
+        let gate = shared.gate.lock().unwrap();
+
+        // discarded by a rollback, or submitted again after a failure
+        batch.retain(|h| shared.pending(*h));
+        alt!(batch.is_empty(), continue);
+
+        if shared.stopping.load(Ordering::Relaxed) {
+            batch.into_iter().for_each(|h| {
+                Event::new("snapshot")
+                    .height(h)
+                    .warn("dropped, the worker is shutting down");
+                shared.set(
+                    h,
+                    SnapStatus::Failed("the worker is shutting down".to_owned()),
+                )
+            });
+            continue;
+        }
+
+        let (latest, coalesced) = coalesce(&batch);
+        coalesced.into_iter().for_each(|h| {
+            Event::new("snapshot")
+                .height(h)
+                .debug(format!("coalesced into {}", latest));
+            shared.set(h, SnapStatus::Coalesced(latest))
+        });
+
+        let mut last = shared.last.lock().unwrap();
+        if !in_order(*last, latest) {
+            let msg = format!(
+                "not later than the last snapshot {}",
+                last.unwrap_or_default()
+            );
+            Event::new("snapshot").height(latest).warn(&msg);
+            drop(last);
+            shared.set(latest, SnapStatus::Failed(msg));
+            continue;
+        }
+        *last = Some(latest);
+        drop(last);
+
+        let cfg = shared.cfg.read().unwrap().clone();
+
+        let start = Instant::now();
+        let ev = Event::new("snapshot").volume(&cfg.volume).height(latest);
+        let created = match create(&cfg, index, latest) {
+            Ok(()) => {
+                ev.since(start).info("snapshot created");
+                shared.set(latest, SnapStatus::Done);
+                true
+            }
+            Err(BtmError::SnapshotExists(_)) => {
+                ev.since(start).warn("the snapshot exists");
+                shared.set(latest, SnapStatus::Exists);
+                false
+            }
+            Err(e) => {
+                ev.since(start).fail(LogLevel::Error, &e);
+                shared.set(latest, SnapStatus::Failed(e.to_string()));
+                false
+            }
+        };
+        drop(gate);
+
+        // the caller is not waiting for this
+        if let Err(e) = prune(&cfg, index) {
+            Event::new("prune")
+                .volume(&cfg.volume)
+                .fail(LogLevel::Warn, &e);
+        }
+
+        // the whole snapshot is read in another thread,
+        // after the cleanup, so that an outdated one is not read at all
+        if created && cfg.verify.digest {
+            digests.push(cfg, latest);
+        }
+    }
+}
+
+// Only the latest height of a batch can be captured,
+// the states of older ones have gone
+fn coalesce(batch: &[u64]) -> (u64, Vec<u64>) {
+    let latest = batch.iter().copied().max().unwrap_or_default();
+    let older = batch.iter().copied().filter(|h| *h != latest).collect();
+    (latest, older)
+}
+
+// Keep the order of snapshots
+#[inline(always)]
+fn in_order(last: Option<u64>, idx: u64) -> bool {
+    !last.is_some_and(|l| idx <= l)
+}
+
+fn create(cfg: &BtmCfg, index: Option<&SnapIndex>, idx: u64) -> Result<()> {
+    let _lk = cfg.lock(&format!("snapshot {}", idx))?;
+
+    // sync data to disk before snapshoting
+    nix::unistd::sync();
+
+    if snapshots(cfg, index)?.contains(&idx) {
+        return Err(BtmError::SnapshotExists(idx));
+    }
+    let cmd = driver::snapshot_cmd(cfg, idx)?;
+    let rec = audit::start(cfg, "snapshot", Some(idx));
+    let res = metrics::timed(cfg, "snapshot", || driver::exec_output(&cmd));
+    audit::finish(cfg, rec, &res);
+    res?;
+
+    if let Some(i) = index {
+        i.insert(idx);
+    }
+    Ok(())
+}
+
+fn prune(cfg: &BtmCfg, index: Option<&SnapIndex>) -> Result<()> {
+    let _lk = cfg.lock("prune")?;
+    let mut snaps = snapshots(cfg, index)?;
+    let mut rec = audit::start(cfg, "prune", None);
+    let destroyed = driver::destroy(cfg, &driver::outdated(cfg, &snaps)?);
+
+    // failures have been logged, and will be retried in the next round
+    if !destroyed.is_empty() {
+        rec.destroyed = destroyed.clone();
+        audit::finish(cfg, rec, &Ok(()));
+    }
+
+    if let Some(i) = index {
+        i.remove(&destroyed);
+    }
+
+    snaps.retain(|h| !destroyed.contains(h));
+    metrics::set_snapshots(cfg, &snaps);
+    metrics::refresh_space_used(cfg);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_into_the_latest() {
+        assert_eq!((25, vec![]), coalesce(&[25]));
+        assert_eq!((45, vec![25, 35]), coalesce(&[25, 45, 35]));
+    }
+
+    #[test]
+    fn keep_the_order() {
+        assert!(in_order(None, 5));
+        assert!(in_order(Some(5), 15));
+        assert!(!in_order(Some(15), 15));
+        assert!(!in_order(Some(15), 5));
+    }
+
+    #[test]
+    fn evict_processed_heights_only() {
+        let mut status = (0..STATUS_HISTORY as u64)
+            .map(|h| (h + 1, SnapStatus::Done))
+            .collect::<BTreeMap<_, _>>();
+        status.insert(0, SnapStatus::Pending);
+        evict(&mut status);
+        assert_eq!(STATUS_HISTORY, status.len());
+        assert_eq!(Some(&SnapStatus::Pending), status.get(&0));
+        assert!(!status.contains_key(&1));
+
+        let mut status = (0..=STATUS_HISTORY as u64)
+            .map(|h| (h, SnapStatus::Pending))
+            .collect::<BTreeMap<_, _>>();
+        evict(&mut status);
+        assert_eq!(STATUS_HISTORY + 1, status.len());
+    }
+
+    #[test]
+    fn skip_heights_out_of_itv() {
+        let cfg = BtmCfg::new("tank/test", Some("zfs")).unwrap();
+        let worker = cfg.spawn_worker().unwrap();
+        worker.submit(10).unwrap();
+        assert_eq!(Some(SnapStatus::Skipped), worker.status(10));
+        assert_eq!(Some(SnapStatus::Skipped), worker.wait(10));
+        assert_eq!(None, worker.status(5));
+    }
+
+    #[test]
+    fn forget_later_heights_on_rollback() {
+        let cfg = BtmCfg::new("tank/test", Some("zfs")).unwrap();
+        let worker = cfg.spawn_worker().unwrap();
+        *worker.shared.last.lock().unwrap() = Some(30);
+        for (h, st) in [
+            (10, SnapStatus::Done),
+            (20, SnapStatus::Coalesced(30)),
+            (30, SnapStatus::Done),
+            (40, SnapStatus::Pending),
+        ] {
+            worker.shared.status.lock().unwrap().insert(h, st);
+        }
+
+        worker.rolled_back(10);
+        assert_eq!(None, *worker.shared.last.lock().unwrap());
+        assert_eq!(Some(SnapStatus::Done), worker.status(10));
+        assert_eq!(None, worker.status(20));
+        assert_eq!(None, worker.status(30));
+        assert!(matches!(worker.status(40), Some(SnapStatus::Failed(_))));
+    }
+}